@@ -0,0 +1,342 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::collections::BTreeMap;
+use toml::{self, Table, Value};
+use rustc_serialize::Decodable;
+use route::Protocol;
+use filter::Filter;
+use output;
+use file_queue::QueueConfig;
+
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS : u64 = 30;
+
+/// An `[input.<name>]` table
+#[derive(RustcDecodable, Debug, Clone, PartialEq)]
+pub struct InputConfig {
+    pub url : String,
+    pub protocol : Option<String>,
+    pub buffer_size : Option<usize>,
+    pub chunk_timeout_secs : Option<u64>,
+    pub max_pending_messages : Option<usize>,
+    pub max_pending_bytes : Option<usize>
+}
+
+/// The options every output understands, whatever its type
+#[derive(RustcDecodable, Debug, Clone, PartialEq)]
+pub struct BufferConfig {
+    pub buffer : Option<String>,
+    pub buffer_max : Option<usize>,
+    pub buffer_path : Option<String>,
+    pub buffer_max_bytes : Option<u64>,
+    pub buffer_segment_bytes : Option<u64>,
+    pub buffer_fsync : Option<String>,
+    pub buffer_fsync_ms : Option<u64>
+}
+
+/// An `[output.<name>]` table
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputConfig {
+    pub kind : output::Kind,
+    pub buffer : BufferConfig
+}
+
+/// A `[route.<name>]` table
+#[derive(RustcDecodable, Debug, Clone, PartialEq)]
+pub struct RouteConfig {
+    pub input : String,
+    pub output : String,
+    pub if_has_field : Option<String>,
+    pub filter : Option<String>
+}
+
+#[derive(RustcDecodable, Debug, Clone)]
+pub struct MetricsConfig {
+    pub url : String
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub shutdown_timeout_secs : u64,
+    pub watch_config : bool,
+    pub metrics : Option<MetricsConfig>,
+    pub inputs : BTreeMap<String, InputConfig>,
+    pub outputs : BTreeMap<String, OutputConfig>,
+    pub routes : BTreeMap<String, RouteConfig>
+}
+
+/// Decodes `v` into a `T`, handing back whichever keys `T` did not use
+pub fn decode<T : Decodable>(v : Value) -> Result<(T, Table), String> {
+    let mut d = toml::Decoder::new(v);
+    let t = T::decode(&mut d).map_err(|e| e.to_string())?;
+    let rest = match d.toml {
+        Some(Value::Table(rest)) => rest,
+        _ => Table::new()
+    };
+    Ok((t, rest))
+}
+
+fn decode_checked<T : Decodable>(what : &str, v : Value, errors : &mut Vec<String>) -> Option<T> {
+    match decode::<T>(v) {
+        Ok((t, rest)) => {
+            for k in rest.keys() {
+                errors.push(format!("{} : unknown key {}", what, k));
+            }
+            Some(t)
+        },
+        Err(e) => { errors.push(format!("{} : {}", what, e)); None }
+    }
+}
+
+fn decode_output(what : &str, v : Value, errors : &mut Vec<String>) -> Option<OutputConfig> {
+    let mut tbl = match v {
+        Value::Table(t) => t,
+        _ => { errors.push(format!("{} should be a table", what)); return None; }
+    };
+    let ty = match tbl.remove("type") {
+        Some(Value::String(ty)) => ty,
+        Some(_) => { errors.push(format!("{} : type should be a string", what)); return None; },
+        None => { errors.push(format!("{} : missing key type", what)); return None; }
+    };
+    let (buffer, rest) = match decode::<BufferConfig>(Value::Table(tbl)) {
+        Ok(b) => b,
+        Err(e) => { errors.push(format!("{} : {}", what, e)); return None; }
+    };
+    match output::Kind::decode(&ty, Value::Table(rest)) {
+        Ok((kind, rest)) => {
+            for k in rest.keys() {
+                errors.push(format!("{} : unknown key {} for a {} output", what, k, ty));
+            }
+            Some(OutputConfig { kind : kind, buffer : buffer })
+        },
+        Err(e) => { errors.push(format!("{} : {}", what, e)); None }
+    }
+}
+
+/// Takes the `[<name>]` section out of the top level table, as a list of its named sub-tables
+fn section(top : &mut Table, name : &str, errors : &mut Vec<String>) -> Vec<(String, Value)> {
+    match top.remove(name) {
+        Some(Value::Table(t)) => t.into_iter().collect(),
+        Some(_) => { errors.push(format!("[{}] should be a table", name)); Vec::new() },
+        None => { errors.push(format!("Config file should contain a [{}] section", name)); Vec::new() }
+    }
+}
+
+/// Resolves a `host:port` url from the config
+pub fn resolve(url : &str) -> Result<SocketAddr, String> {
+    match url.to_socket_addrs() {
+        Ok(mut addrs) => addrs.next().ok_or(format!("{} did not resolve to any address", url)),
+        Err(e) => Err(format!("{} is not a valid address : {}", url, e))
+    }
+}
+
+/// Resolves a secret such as a password, given directly as `<what>`, as `<what>_env`, the name of
+/// an environment variable holding it, or as `<what>_file`, the path of a file holding it such as
+/// a mounted kubernetes secret.  A trailing newline in the file is ignored.
+pub fn secret(what : &str, value : &Option<String>, var : &Option<String>, path : &Option<String>) -> Result<Option<String>, String> {
+    match (value, var, path) {
+        (&None, &None, &None) => Ok(None),
+        (&Some(ref v), &None, &None) => Ok(Some(v.clone())),
+        (&None, &Some(ref var), &None) => env::var(var).map(Some).map_err(|e| format!("{}_env {} : {}", what, var, e)),
+        (&None, &None, &Some(ref path)) => {
+            let mut s = String::new();
+            File::open(path).and_then(|mut f| f.read_to_string(&mut s)).map_err(|e| format!("{}_file {} : {}", what, path, e))?;
+            Ok(Some(s.trim_right_matches(|c| c == '\n' || c == '\r').to_owned()))
+        },
+        _ => Err(format!("only one of {0}, {0}_env and {0}_file may be given", what))
+    }
+}
+
+impl Config {
+    pub fn load(path : &str) -> Result<Config, Vec<String>> {
+        let mut configstr = String::new();
+        if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut configstr)) {
+            return Err(vec![format!("Failed to read {} : {}", path, e)]);
+        }
+        Config::parse(&configstr)
+    }
+
+    /// Parses and validates a config, collecting every problem found rather than stopping at the first
+    pub fn parse(configstr : &str) -> Result<Config, Vec<String>> {
+        let mut tp = toml::Parser::new(configstr);
+        let mut top = match tp.parse() {
+            Some(t) => t,
+            None => return Err(tp.errors.iter().map(|e| {
+                let (line, col) = tp.to_linecol(e.lo);
+                format!("line {}, column {} : {}", line + 1, col + 1, e.desc)
+            }).collect())
+        };
+
+        let mut errors = Vec::new();
+        let shutdown_timeout_secs = match top.remove("shutdown_timeout_secs") {
+            None => DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            Some(Value::Integer(n)) if n >= 0 => n as u64,
+            Some(v) => {
+                errors.push(format!("shutdown_timeout_secs should be a positive integer, not {}", v));
+                DEFAULT_SHUTDOWN_TIMEOUT_SECS
+            }
+        };
+        let watch_config = match top.remove("watch_config") {
+            None => false,
+            Some(Value::Boolean(b)) => b,
+            Some(v) => {
+                errors.push(format!("watch_config should be true or false, not {}", v));
+                false
+            }
+        };
+        let metrics = top.remove("metrics").and_then(|m| decode_checked::<MetricsConfig>("[metrics]", m, &mut errors));
+
+        let mut inputs = BTreeMap::new();
+        for (name, v) in section(&mut top, "input", &mut errors) {
+            if let Some(i) = decode_checked::<InputConfig>(&format!("[input.{}]", name), v, &mut errors) {
+                inputs.insert(name, i);
+            }
+        }
+        let mut outputs = BTreeMap::new();
+        for (name, v) in section(&mut top, "output", &mut errors) {
+            if let Some(o) = decode_output(&format!("[output.{}]", name), v, &mut errors) {
+                outputs.insert(name, o);
+            }
+        }
+        let mut routes = BTreeMap::new();
+        for (name, v) in section(&mut top, "route", &mut errors) {
+            if let Some(r) = decode_checked::<RouteConfig>(&format!("[route.{}]", name), v, &mut errors) {
+                routes.insert(name, r);
+            }
+        }
+        for k in top.keys() {
+            errors.push(format!("unknown top level key or section {}", k));
+        }
+
+        let config = Config {
+            shutdown_timeout_secs : shutdown_timeout_secs,
+            watch_config : watch_config,
+            metrics : metrics,
+            inputs : inputs,
+            outputs : outputs,
+            routes : routes
+        };
+        errors.extend(config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Checks the values which decoded but may still be wrong, along with any files they refer to.
+    /// Addresses are resolved but nothing is bound or connected to.
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(ref m) = self.metrics {
+            if let Err(e) = resolve(&m.url) {
+                errors.push(format!("[metrics] : {}", e));
+            }
+        }
+        for (name, i) in self.inputs.iter() {
+            if let Err(e) = resolve(&i.url) {
+                errors.push(format!("[input.{}] : {}", name, e));
+            }
+            if let Some(ref p) = i.protocol {
+                if Protocol::parse(p).is_none() {
+                    errors.push(format!("[input.{}] : {} is not a valid protocol", name, p));
+                }
+            }
+        }
+        for (name, o) in self.outputs.iter() {
+            let b = &o.buffer;
+            match b.buffer.as_ref().map(|b| b.as_str()) {
+                None | Some("memory") | Some("disk") => {},
+                Some(b) => errors.push(format!("[output.{}] : {} is not a valid buffer type", name, b))
+            }
+            match b.buffer_fsync.as_ref().map(|f| f.as_str()) {
+                None | Some("always") | Some("interval") | Some("never") => {},
+                Some(f) => errors.push(format!("[output.{}] : {} is not a valid buffer_fsync policy", name, f))
+            }
+            let queue_defaults = QueueConfig::default();
+            let max_bytes = b.buffer_max_bytes.unwrap_or(queue_defaults.max_bytes);
+            let segment_bytes = b.buffer_segment_bytes.unwrap_or(queue_defaults.segment_bytes);
+            if max_bytes <= segment_bytes {
+                errors.push(format!("[output.{}] : buffer_max_bytes {} must be larger than buffer_segment_bytes {}",
+                                    name, max_bytes, segment_bytes));
+            }
+            for e in o.kind.validate() {
+                errors.push(format!("[output.{}] : {}", name, e));
+            }
+        }
+        for (name, r) in self.routes.iter() {
+            if !self.inputs.contains_key(&r.input) {
+                errors.push(format!("[route.{}] : there is no input named {}", name, r.input));
+            }
+            if !self.outputs.contains_key(&r.output) {
+                errors.push(format!("[route.{}] : there is no output named {}", name, r.output));
+            }
+            if let Some(ref f) = r.filter {
+                if let Err(e) = Filter::parse(f) {
+                    errors.push(format!("[route.{}] : invalid filter : {}", name, e));
+                }
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, secret};
+    use std::env;
+
+    const BASE : &'static str = r#"
+[input.gelf]
+url = "127.0.0.1:5555"
+
+[output.out]
+type = "stdout"
+brief = true
+
+[route.default]
+input = "gelf"
+output = "out"
+"#;
+
+#[test]
+    fn config_valid() {
+        let config = Config::parse(BASE).unwrap();
+        assert_eq!(config.shutdown_timeout_secs, 30);
+        assert_eq!(config.routes["default"].output, "out");
+    }
+
+#[test]
+    fn config_reports_every_error() {
+        let bad = BASE.replace("output = \"out\"", "output = \"nowhere\"\nif_has_key = \"pd_log\"")
+                      .replace("brief = true", "brief = true\nbuffer = \"tape\"\nbuffer_max_bytes = 1024\nbuffer_segment_bytes = 4096")
+                      + "\n[rout.other]\ninput = \"gelf\"\n";
+        let errors = Config::parse(&bad).unwrap_err();
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        assert!(errors.iter().any(|e| e == "[output.out] : buffer_max_bytes 1024 must be larger than buffer_segment_bytes 4096"));
+        assert!(errors.iter().any(|e| e == "[route.default] : unknown key if_has_key"));
+        assert!(errors.iter().any(|e| e == "[route.default] : there is no output named nowhere"));
+        assert!(errors.iter().any(|e| e == "[output.out] : tape is not a valid buffer type"));
+        assert!(errors.iter().any(|e| e == "unknown top level key or section rout"));
+    }
+
+#[test]
+    fn config_missing_url() {
+        let errors = Config::parse(&BASE.replace("url = \"127.0.0.1:5555\"", "")).unwrap_err();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].starts_with("[input.gelf] : "));
+    }
+
+#[test]
+    fn config_secrets() {
+        env::set_var("LOUT_TEST_SECRET", "hunter2");
+        assert_eq!(secret("password", &None, &Some("LOUT_TEST_SECRET".to_owned()), &None), Ok(Some("hunter2".to_owned())));
+        assert_eq!(secret("password", &Some("plain".to_owned()), &None, &None), Ok(Some("plain".to_owned())));
+        assert_eq!(secret("password", &None, &None, &None), Ok(None));
+        assert!(secret("password", &None, &Some("LOUT_TEST_NO_SUCH_VAR".to_owned()), &None).is_err());
+        assert!(secret("password", &None, &None, &Some("/nonexistent/lout/secret".to_owned())).is_err());
+        assert_eq!(secret("password", &Some("a".to_owned()), &Some("LOUT_TEST_SECRET".to_owned()), &None),
+                   Err("only one of password, password_env and password_file may be given".to_owned()));
+    }
+}
@@ -1,11 +1,454 @@
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write, Seek, SeekFrom, BufReader, ErrorKind, Result};
+use std::time::{Duration, Instant};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use std::fs::File;
-use std::path::Path;
-use std::io::Result;
+/// How often appended records are forced to disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fsync {
+    Always,
+    Interval(Duration),
+    Never
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    pub max_bytes : u64,
+    pub segment_bytes : u64,
+    pub fsync : Fsync
+}
+
+impl Default for QueueConfig {
+    fn default() -> QueueConfig {
+        QueueConfig {
+            max_bytes : 1024 * 1024 * 1024,
+            segment_bytes : 64 * 1024 * 1024,
+            fsync : Fsync::Interval(Duration::from_secs(1))
+        }
+    }
+}
+
+const SEGMENT_EXT : &'static str = "seg";
+const CURSOR_FILE : &'static str = "cursor";
+const HEADER_SZ : u64 = 4;
+// the read position is written out after this many pops, or whenever we sync
+const COMMIT_EVERY : usize = 1000;
+
+/// A persistent FIFO of byte records, stored as a directory of append-only segment files.
+///
+/// Each record is a little endian u32 length followed by the payload.  Segments are named
+/// by a sequence number and are deleted once the reader has moved past them. The read
+/// position is kept in a `cursor` file, so after a crash records may be delivered again,
+/// but are never lost once `sync` has returned.
+pub struct FileQueue {
+    dir : PathBuf,
+    cfg : QueueConfig,
+    write_seg : u64,
+    writer : File,
+    write_off : u64,
+    read_seg : u64,
+    read_off : u64,
+    reader : BufReader<File>,
+    head : Option<Vec<u8>>,
+    disk_bytes : u64,
+    unsynced : bool,
+    last_sync : Instant,
+    uncommitted : usize
+}
+
+fn segment_path(dir : &Path, seq : u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", seq, SEGMENT_EXT))
+}
+
+fn list_segments(dir : &Path) -> Result<Vec<u64>> {
+    let mut segs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|e| e == SEGMENT_EXT).unwrap_or(false) {
+            if let Some(seq) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) {
+                segs.push(seq);
+            }
+        }
+    }
+    segs.sort();
+    Ok(segs)
+}
+
+/// Returns the length of the segment up to the end of the last complete record
+fn valid_len(path : &Path) -> Result<u64> {
+    let f = File::open(path)?;
+    let len = f.metadata()?.len();
+    let mut r = BufReader::new(f);
+    let mut off = 0;
+    while off + HEADER_SZ <= len {
+        let sz = r.read_u32::<LittleEndian>()? as u64;
+        if off + HEADER_SZ + sz > len {
+            break;
+        }
+        r.seek(SeekFrom::Current(sz as i64))?;
+        off += HEADER_SZ + sz;
+    }
+    Ok(off)
+}
+
+fn open_reader(dir : &Path, seq : u64, off : u64) -> Result<BufReader<File>> {
+    let mut f = File::open(segment_path(dir, seq))?;
+    f.seek(SeekFrom::Start(off))?;
+    Ok(BufReader::new(f))
+}
+
+fn open_writer(dir : &Path, seq : u64) -> Result<File> {
+    OpenOptions::new().create(true).append(true).open(segment_path(dir, seq))
+}
+
+impl FileQueue {
+
+    pub fn open<P: AsRef<Path>>(dir : P, cfg : QueueConfig) -> Result<FileQueue> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut segs = list_segments(&dir)?;
+        if segs.is_empty() {
+            open_writer(&dir, 0)?;
+            segs.push(0);
+        }
+        let write_seg = *segs.last().unwrap();
+
+        let (mut read_seg, mut read_off) = match File::open(dir.join(CURSOR_FILE)) {
+            Ok(mut f) => (f.read_u64::<LittleEndian>().unwrap_or(0), f.read_u64::<LittleEndian>().unwrap_or(0)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (segs[0], 0),
+            Err(e) => return Err(e)
+        };
+        if !segs.contains(&read_seg) {
+            read_seg = *segs.iter().find(|s| **s > read_seg).unwrap_or(&write_seg);
+            read_off = 0;
+        }
+        for seq in segs.iter().filter(|s| **s < read_seg) {
+            fs::remove_file(segment_path(&dir, *seq))?;
+        }
+
+        let mut disk_bytes = 0;
+        let mut write_off = 0;
+        for seq in segs.iter().filter(|s| **s >= read_seg) {
+            // anything after the last whole record was a write interrupted by a crash
+            let path = segment_path(&dir, *seq);
+            let len = valid_len(&path)?;
+            if len < fs::metadata(&path)?.len() {
+                error!("Dropping the partial record at the end of {:?}", path);
+                OpenOptions::new().write(true).open(&path)?.set_len(len)?;
+            }
+            // without fsync the cursor can have reached the disk ahead of the records it points past
+            if *seq == read_seg && read_off > len {
+                read_off = len;
+            }
+            if *seq == write_seg {
+                write_off = len;
+            }
+            disk_bytes += len;
+        }
+
+        Ok(FileQueue {
+            reader : open_reader(&dir, read_seg, read_off)?,
+            writer : open_writer(&dir, write_seg)?,
+            dir : dir,
+            cfg : cfg,
+            write_seg : write_seg,
+            write_off : write_off,
+            read_seg : read_seg,
+            read_off : read_off,
+            head : None,
+            disk_bytes : disk_bytes,
+            unsynced : false,
+            last_sync : Instant::now(),
+            uncommitted : 0
+        })
+    }
+
+    /// Appends a record, failing with `ErrorKind::Other` if the queue is at its size limit
+    pub fn push(&mut self, data : &[u8]) -> Result<()> {
+        let sz = HEADER_SZ + data.len() as u64;
+        // rotate first, so records already read from the current segment don't count against the limit
+        if self.write_off > 0 && self.write_off + sz > self.cfg.segment_bytes {
+            self.rotate()?;
+        }
+        if self.disk_bytes + sz > self.cfg.max_bytes {
+            return Err(io::Error::new(ErrorKind::Other, "queue is full"));
+        }
+        // one write per record, so a reader never sees a header without its payload
+        let mut rec = Vec::with_capacity(sz as usize);
+        rec.write_u32::<LittleEndian>(data.len() as u32)?;
+        rec.extend_from_slice(data);
+        self.writer.write_all(&rec)?;
+        self.write_off += sz;
+        self.disk_bytes += sz;
+        self.unsynced = true;
+
+        match self.cfg.fsync {
+            Fsync::Always => self.sync(),
+            Fsync::Interval(d) if self.last_sync.elapsed() >= d => self.sync(),
+            _ => Ok(())
+        }
+    }
+
+    /// Starts a new write segment, deleting the old one straight away if every record in it has been read
+    fn rotate(&mut self) -> Result<()> {
+        self.writer.sync_all()?;
+        let done = self.write_seg;
+        let consumed = self.head.is_none() && self.read_seg == done && self.read_off >= self.write_off;
+        self.write_seg += 1;
+        self.writer = open_writer(&self.dir, self.write_seg)?;
+        self.write_off = 0;
+        if consumed {
+            self.read_seg = self.write_seg;
+            self.read_off = 0;
+            self.reader = open_reader(&self.dir, self.read_seg, 0)?;
+            self.commit()?;
+            let path = segment_path(&self.dir, done);
+            self.disk_bytes -= fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the oldest record without removing it
+    pub fn peek(&mut self) -> Result<Option<&[u8]>> {
+        if self.head.is_none() {
+            loop {
+                let end = if self.read_seg == self.write_seg { self.write_off } else { u64::max_value() };
+                if self.read_off + HEADER_SZ <= end {
+                    match self.reader.read_u32::<LittleEndian>() {
+                        Ok(sz) => {
+                            let mut buf = vec![0u8; sz as usize];
+                            self.reader.read_exact(&mut buf)?;
+                            self.head = Some(buf);
+                            break;
+                        },
+                        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {},
+                        Err(e) => return Err(e)
+                    }
+                }
+                if self.read_seg == self.write_seg {
+                    return Ok(None);
+                }
+                // finished with this segment
+                let done = self.read_seg;
+                self.read_seg += 1;
+                self.read_off = 0;
+                self.reader = open_reader(&self.dir, self.read_seg, 0)?;
+                self.commit()?;
+                let path = segment_path(&self.dir, done);
+                self.disk_bytes -= fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(self.head.as_ref().map(|h| h.as_slice()))
+    }
+
+    /// Removes the oldest record
+    pub fn pop(&mut self) -> Result<()> {
+        if self.head.is_none() {
+            self.peek()?;
+        }
+        if let Some(h) = self.head.take() {
+            self.read_off += HEADER_SZ + h.len() as u64;
+            self.uncommitted += 1;
+            if self.uncommitted >= COMMIT_EVERY {
+                self.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none() && self.read_seg == self.write_seg && self.read_off >= self.write_off
+    }
+
+    /// Bytes currently held on disk, including records already read from the oldest segment
+    pub fn disk_bytes(&self) -> u64 {
+        self.disk_bytes
+    }
+
+    /// Syncs if the fsync interval has passed, for callers that have stopped pushing
+    pub fn tick(&mut self) -> Result<()> {
+        match self.cfg.fsync {
+            Fsync::Interval(d) if (self.unsynced || self.uncommitted > 0) && self.last_sync.elapsed() >= d => self.sync(),
+            _ => Ok(())
+        }
+    }
+
+    /// Forces appended records to disk and records the read position
+    pub fn sync(&mut self) -> Result<()> {
+        if self.unsynced {
+            self.writer.sync_data()?;
+            self.unsynced = false;
+        }
+        self.last_sync = Instant::now();
+        self.commit()
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", CURSOR_FILE));
+        {
+            let mut f = File::create(&tmp)?;
+            f.write_u64::<LittleEndian>(self.read_seg)?;
+            f.write_u64::<LittleEndian>(self.read_off)?;
+            if self.cfg.fsync != Fsync::Never {
+                f.sync_all()?;
+            }
+        }
+        fs::rename(tmp, self.dir.join(CURSOR_FILE))?;
+        self.uncommitted = 0;
+        Ok(())
+    }
+}
+
+impl Drop for FileQueue {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            error!("Failed to sync queue at {:?} : {}", self.dir, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileQueue, QueueConfig, Fsync, segment_path, list_segments};
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use rand::{self, Rng};
+
+    fn tmpdir() -> PathBuf {
+        let mut d = env::temp_dir();
+        d.push(format!("lout-fq-{}", rand::thread_rng().gen::<u64>()));
+        d
+    }
+
+    fn cfg(max_bytes : u64, segment_bytes : u64) -> QueueConfig {
+        QueueConfig { max_bytes : max_bytes, segment_bytes : segment_bytes, fsync : Fsync::Never }
+    }
+
+#[test]
+    fn queue_fifo_across_segments() {
+        let dir = tmpdir();
+        {
+            let mut q = FileQueue::open(&dir, cfg(1024 * 1024, 64)).unwrap();
+            assert!(q.is_empty());
+            for i in 0..20 {
+                q.push(format!("message number {}", i).as_bytes()).unwrap();
+            }
+            for i in 0..20 {
+                assert_eq!(q.peek().unwrap().unwrap(), format!("message number {}", i).as_bytes());
+                q.pop().unwrap();
+            }
+            assert!(q.peek().unwrap().is_none());
+            assert!(q.is_empty());
+        }
+        // consumed segments are cleaned up, leaving only the one being written
+        assert_eq!(fs::read_dir(&dir).unwrap().filter(|e| e.as_ref().unwrap().path().extension().map(|x| x == "seg").unwrap_or(false)).count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+#[test]
+    fn queue_survives_reopen() {
+        let dir = tmpdir();
+        {
+            let mut q = FileQueue::open(&dir, cfg(1024 * 1024, 100)).unwrap();
+            for i in 0..10 {
+                q.push(format!("{}", i).as_bytes()).unwrap();
+            }
+            for _ in 0..4 {
+                q.pop().unwrap();
+            }
+        }
+        {
+            let mut q = FileQueue::open(&dir, cfg(1024 * 1024, 100)).unwrap();
+            assert_eq!(q.peek().unwrap().unwrap(), b"4");
+            q.push(b"10").unwrap();
+            let mut rest = Vec::new();
+            while let Some(r) = q.peek().unwrap().map(|r| r.to_vec()) {
+                rest.push(String::from_utf8(r).unwrap());
+                q.pop().unwrap();
+            }
+            assert_eq!(rest, vec!["4", "5", "6", "7", "8", "9", "10"]);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+#[test]
+    fn queue_enforces_max_bytes() {
+        let dir = tmpdir();
+        {
+            let mut q = FileQueue::open(&dir, cfg(30, 1024)).unwrap();
+            q.push(b"0123456789").unwrap();
+            q.push(b"0123456789").unwrap();
+            assert!(q.push(b"0123456789").is_err());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-pub type FileQueue = File;
+#[test]
+    fn queue_reclaims_read_segment_when_full() {
+        let dir = tmpdir();
+        {
+            // a segment holds two records, and the queue can't hold a third beside them
+            let mut q = FileQueue::open(&dir, cfg(30, 28)).unwrap();
+            for i in 0..10 {
+                q.push(format!("message {}", i).as_bytes()).unwrap();
+                assert_eq!(q.peek().unwrap().unwrap(), format!("message {}", i).as_bytes());
+                q.pop().unwrap();
+            }
+            assert!(q.disk_bytes() <= 28);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
 
+#[test]
+    fn queue_clamps_cursor_past_end() {
+        let dir = tmpdir();
+        {
+            let mut q = FileQueue::open(&dir, cfg(1024 * 1024, 1024)).unwrap();
+            for i in 0..3 {
+                q.push(format!("message {}", i).as_bytes()).unwrap();
+                q.pop().unwrap();
+            }
+        }
+        // the cursor was written, but the records it points past never reached the disk
+        let seg = segment_path(&dir, list_segments(&dir).unwrap()[0]);
+        OpenOptions::new().write(true).open(&seg).unwrap().set_len(13).unwrap();
+        {
+            let mut q = FileQueue::open(&dir, cfg(1024 * 1024, 1024)).unwrap();
+            assert!(q.peek().unwrap().is_none());
+            q.push(b"new").unwrap();
+            assert_eq!(q.peek().unwrap().unwrap(), b"new");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-pub fn new<P: AsRef<Path>>(path: P) -> Result<FileQueue> {
-    File::open(path)
+#[test]
+    fn queue_drops_partial_record_of_old_segment() {
+        let dir = tmpdir();
+        {
+            let mut q = FileQueue::open(&dir, cfg(1024 * 1024, 28)).unwrap();
+            for i in 0..4 {
+                q.push(format!("message {}", i).as_bytes()).unwrap();
+            }
+        }
+        // a record cut short at the end of a segment which is no longer being written
+        let segs = list_segments(&dir).unwrap();
+        assert!(segs.len() > 1);
+        let mut f = OpenOptions::new().append(true).open(segment_path(&dir, segs[0])).unwrap();
+        f.write_all(&[100, 0, 0, 0, b'm', b'e']).unwrap();
+        {
+            let mut q = FileQueue::open(&dir, cfg(1024 * 1024, 28)).unwrap();
+            for i in 0..4 {
+                assert_eq!(q.peek().unwrap().unwrap(), format!("message {}", i).as_bytes());
+                q.pop().unwrap();
+            }
+            assert!(q.peek().unwrap().is_none());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,370 @@
+use std::fmt::{self, Debug, Formatter};
+use std::iter::Peekable;
+use std::str::Chars;
+use serde_json::Value as JValue;
+use serde_json::Number;
+use regex::Regex;
+
+/// A predicate over a message, built from a route's `filter` expression.
+///
+/// Fields are addressed with JSON pointers (`/_service`, `/user/id`); a bare
+/// word such as `level` is shorthand for `/level`.  Supported forms are
+///
+/// ```text
+///   /field                      the field is present
+///   /field == "value"           also != < <= > >=
+///   /field =~ "^pay.*"          regex match, !~ to negate
+///   /field in [1, 2, "three"]
+///   a and b, a or b, not a, ( a )
+/// ```
+pub enum Filter {
+    Exists(String),
+    Compare(String, CmpOp, JValue),
+    Matches(String, Regex),
+    In(String, Vec<JValue>),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge
+}
+
+impl Filter {
+
+    pub fn parse(expr : &str) -> Result<Filter, String> {
+        let mut p = ExprParser { tokens : tokenize(expr)?, pos : 0 };
+        let f = p.or_expr()?;
+        match p.peek() {
+            None => Ok(f),
+            Some(t) => Err(format!("unexpected {:?} in filter '{}'", t, expr))
+        }
+    }
+
+    pub fn matches(&self, msg : &JValue) -> bool {
+        match *self {
+            Filter::Exists(ref ptr) => msg.pointer(ptr).is_some(),
+            Filter::Compare(ref ptr, op, ref lit) => {
+                match msg.pointer(ptr) {
+                    Some(v) => compare(v, op, lit),
+                    None => op == CmpOp::Ne
+                }
+            },
+            Filter::Matches(ref ptr, ref re) => {
+                msg.pointer(ptr).and_then(|v| v.as_str()).map(|s| re.is_match(s)).unwrap_or(false)
+            },
+            Filter::In(ref ptr, ref list) => {
+                msg.pointer(ptr).map(|v| list.iter().any(|l| compare(v, CmpOp::Eq, l))).unwrap_or(false)
+            },
+            Filter::Not(ref f) => !f.matches(msg),
+            Filter::And(ref a, ref b) => a.matches(msg) && b.matches(msg),
+            Filter::Or(ref a, ref b) => a.matches(msg) || b.matches(msg)
+        }
+    }
+}
+
+fn compare(v : &JValue, op : CmpOp, lit : &JValue) -> bool {
+    if let (Some(a), Some(b)) = (v.as_f64(), lit.as_f64()) {
+        return match op {
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+            CmpOp::Lt => a < b,
+            CmpOp::Le => a <= b,
+            CmpOp::Gt => a > b,
+            CmpOp::Ge => a >= b
+        };
+    }
+    if let (Some(a), Some(b)) = (v.as_str(), lit.as_str()) {
+        return match op {
+            CmpOp::Eq => a == b,
+            CmpOp::Ne => a != b,
+            CmpOp::Lt => a < b,
+            CmpOp::Le => a <= b,
+            CmpOp::Gt => a > b,
+            CmpOp::Ge => a >= b
+        };
+    }
+    match op {
+        CmpOp::Eq => v == lit,
+        CmpOp::Ne => v != lit,
+        _ => false
+    }
+}
+
+impl Debug for Filter {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Filter::Exists(ref ptr) => write!(f, "{}", ptr),
+            Filter::Compare(ref ptr, op, ref lit) => write!(f, "{} {:?} {}", ptr, op, lit),
+            Filter::Matches(ref ptr, ref re) => write!(f, "{} =~ {:?}", ptr, re.as_str()),
+            Filter::In(ref ptr, ref list) => write!(f, "{} in {:?}", ptr, list),
+            Filter::Not(ref a) => write!(f, "not ({:?})", a),
+            Filter::And(ref a, ref b) => write!(f, "({:?}) and ({:?})", a, b),
+            Filter::Or(ref a, ref b) => write!(f, "({:?}) or ({:?})", a, b)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Cmp(CmpOp),
+    Match,
+    NotMatch,
+    Literal(JValue),
+    Word(String)
+}
+
+fn is_word_char(c : char) -> bool {
+    !c.is_whitespace() && !"()[],=!<>\"".contains(c)
+}
+
+fn tokenize(expr : &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars : Peekable<Chars> = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        let tok = match c {
+            ' ' | '\t' | '\n' | '\r' => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            ',' => Token::Comma,
+            '=' | '!' | '<' | '>' => {
+                let next = chars.peek().cloned();
+                let tok = match (c, next) {
+                    ('=', Some('=')) => Token::Cmp(CmpOp::Eq),
+                    ('=', Some('~')) => Token::Match,
+                    ('!', Some('=')) => Token::Cmp(CmpOp::Ne),
+                    ('!', Some('~')) => Token::NotMatch,
+                    ('<', Some('=')) => Token::Cmp(CmpOp::Le),
+                    ('>', Some('=')) => Token::Cmp(CmpOp::Ge),
+                    ('<', _) => { tokens.push(Token::Cmp(CmpOp::Lt)); continue },
+                    ('>', _) => { tokens.push(Token::Cmp(CmpOp::Gt)); continue },
+                    _ => return Err(format!("unexpected '{}' in filter '{}'", c, expr))
+                };
+                chars.next();
+                tok
+            },
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some('r') => s.push('\r'),
+                            Some(e) if e == '"' || e == '\\' => s.push(e),
+                            // anything else is kept as written, so regexes such as "\d+" need no doubling
+                            Some(e) => { s.push('\\'); s.push(e) },
+                            None => return Err(format!("unterminated string in filter '{}'", expr))
+                        },
+                        Some(ch) => s.push(ch),
+                        None => return Err(format!("unterminated string in filter '{}'", expr))
+                    }
+                }
+                Token::Literal(JValue::String(s))
+            },
+            _ => {
+                let mut w = c.to_string();
+                while let Some(&ch) = chars.peek() {
+                    if !is_word_char(ch) { break; }
+                    w.push(ch);
+                    chars.next();
+                }
+                match w.as_str() {
+                    "true" => Token::Literal(JValue::Bool(true)),
+                    "false" => Token::Literal(JValue::Bool(false)),
+                    "null" => Token::Literal(JValue::Null),
+                    _ => {
+                        if let Ok(i) = w.parse::<i64>() {
+                            Token::Literal(JValue::Number(Number::from(i)))
+                        } else if let Some(n) = w.parse::<f64>().ok().and_then(Number::from_f64) {
+                            Token::Literal(JValue::Number(n))
+                        } else {
+                            Token::Word(w)
+                        }
+                    }
+                }
+            }
+        };
+        tokens.push(tok);
+    }
+    Ok(tokens)
+}
+
+struct ExprParser {
+    tokens : Vec<Token>,
+    pos : usize
+}
+
+impl ExprParser {
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn expect(&mut self, tok : Token) -> Result<(), String> {
+        match self.next() {
+            Some(ref t) if *t == tok => Ok(()),
+            other => Err(format!("expected {:?}, found {:?}", tok, other))
+        }
+    }
+
+    fn keyword(&self, kw : &str) -> bool {
+        match self.peek() {
+            Some(&Token::Word(ref w)) => w == kw,
+            _ => false
+        }
+    }
+
+    fn or_expr(&mut self) -> Result<Filter, String> {
+        let mut lhs = self.and_expr()?;
+        while self.keyword("or") {
+            self.next();
+            let rhs = self.and_expr()?;
+            lhs = Filter::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn and_expr(&mut self) -> Result<Filter, String> {
+        let mut lhs = self.unary()?;
+        while self.keyword("and") {
+            self.next();
+            let rhs = self.unary()?;
+            lhs = Filter::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Filter, String> {
+        if self.keyword("not") {
+            self.next();
+            return Ok(Filter::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.next();
+            let f = self.or_expr()?;
+            self.expect(Token::RParen)?;
+            return Ok(f);
+        }
+        let ptr = match self.next() {
+            Some(Token::Word(w)) => if w.starts_with('/') { w } else { format!("/{}", w) },
+            other => return Err(format!("expected a field, found {:?}", other))
+        };
+        match self.peek().cloned() {
+            Some(Token::Cmp(op)) => {
+                self.next();
+                Ok(Filter::Compare(ptr, op, self.literal()?))
+            },
+            Some(Token::Match) | Some(Token::NotMatch) => {
+                let negate = self.next() == Some(Token::NotMatch);
+                let re = match self.literal()? {
+                    JValue::String(s) => Regex::new(&s).map_err(|e| e.to_string())?,
+                    other => return Err(format!("expected a regex string, found {}", other))
+                };
+                let f = Filter::Matches(ptr, re);
+                Ok(if negate { Filter::Not(Box::new(f)) } else { f })
+            },
+            Some(Token::Word(ref w)) if w == "in" => {
+                self.next();
+                self.expect(Token::LBracket)?;
+                let mut list = Vec::new();
+                if self.peek() != Some(&Token::RBracket) {
+                    loop {
+                        list.push(self.literal()?);
+                        if self.peek() == Some(&Token::Comma) {
+                            self.next();
+                        } else {
+                            break;
+                        }
+                    }
+                }
+                self.expect(Token::RBracket)?;
+                Ok(Filter::In(ptr, list))
+            },
+            _ => Ok(Filter::Exists(ptr))
+        }
+    }
+
+    fn literal(&mut self) -> Result<JValue, String> {
+        match self.next() {
+            Some(Token::Literal(v)) => Ok(v),
+            other => Err(format!("expected a value, found {:?}", other))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use serde_json;
+    use serde_json::Value as JValue;
+
+    fn msg() -> JValue {
+        serde_json::from_str(r#"{"version":"1.1","host":"web-1","level":3,
+            "_service":"payments","_user":{"id":42}}"#).unwrap()
+    }
+
+#[test]
+    fn filter_compare() {
+        let m = msg();
+        assert!(Filter::parse(r#"/_service == "payments""#).unwrap().matches(&m));
+        assert!(!Filter::parse(r#"/_service != "payments""#).unwrap().matches(&m));
+        assert!(Filter::parse("level <= 3").unwrap().matches(&m));
+        assert!(!Filter::parse("level < 3").unwrap().matches(&m));
+        assert!(Filter::parse("/_user/id > 41.5").unwrap().matches(&m));
+        assert!(Filter::parse(r#"/missing != "x""#).unwrap().matches(&m));
+        assert!(!Filter::parse("/missing < 10").unwrap().matches(&m));
+    }
+
+#[test]
+    fn filter_regex_and_in() {
+        let m = msg();
+        assert!(Filter::parse(r#"host =~ "^web-\\d+$""#).unwrap().matches(&m));
+        assert!(!Filter::parse(r#"host !~ "^web""#).unwrap().matches(&m));
+        assert!(Filter::parse(r#"host =~ "^web-\d+$""#).unwrap().matches(&m));
+        // an unknown escape keeps its backslash, rather than quietly matching "web-1"
+        assert!(!Filter::parse(r#"host == "web\-1""#).unwrap().matches(&m));
+        assert!(Filter::parse(r#"host != "web\"1\\""#).unwrap().matches(&m));
+        assert!(Filter::parse(r#"/_service in ["billing", "payments"]"#).unwrap().matches(&m));
+        assert!(!Filter::parse("level in [0, 1, 2]").unwrap().matches(&m));
+    }
+
+#[test]
+    fn filter_combinators() {
+        let m = msg();
+        assert!(Filter::parse(r#"level <= 3 and /_service == "payments""#).unwrap().matches(&m));
+        assert!(Filter::parse(r#"level < 3 or not (/_service == "billing")"#).unwrap().matches(&m));
+        assert!(!Filter::parse("/_user and not /_user/id").unwrap().matches(&m));
+        assert!(Filter::parse("a or b and c").is_ok());
+    }
+
+#[test]
+    fn filter_errors() {
+        assert!(Filter::parse("level <=").is_err());
+        assert!(Filter::parse(r#"host =~ "(""#).is_err());
+        assert!(Filter::parse(r#"host == "open"#).is_err());
+        assert!(Filter::parse("(level > 1").is_err());
+        assert!(Filter::parse("level > 1 level").is_err());
+    }
+}
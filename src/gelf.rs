@@ -4,12 +4,12 @@ use std::slice;
 use std::cmp::min;
 use std::ptr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::io::{self, Read, Write, ErrorKind, BufReader};
 use flate2::read::GzDecoder;
 use flate2::write::{GzEncoder};
 use flate2::Compression;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use serde_json::value::Value as JValue;
 use serde_json::de;
 use bytes::{BytesMut, BufMut};
@@ -61,13 +61,14 @@ chunks : Vec<Option<(BytesMut, usize)>>,
        count : usize,
        rd_offset : usize,
        rd_index : usize,
+       size : usize,
        timestamp : Instant
 }
 
 impl Message {
     pub fn new(sz : u8) -> Message {
         let v : Vec<Option<(BytesMut, usize)>> = vec![None; sz as usize];
-        Message { chunks : v, rd_offset : 0, rd_index : 0, count : 0, timestamp : Instant::now() }
+        Message { chunks : v, rd_offset : 0, rd_index : 0, count : 0, size : 0, timestamp : Instant::now() }
     }
 
     pub fn new_with_buf(sz : u8, buf : BytesMut, idx : u8, offset : usize) -> Option<Message> {
@@ -75,8 +76,9 @@ impl Message {
             return None
         }
         let mut v : Vec<Option<(BytesMut, usize)>> = vec![None; sz as usize];
+        let size = buf.len();
         v[idx as usize] = Some((buf, offset)); 
-        Some(Message { chunks : v, rd_offset : 0, rd_index : 0, count : 1, timestamp : Instant::now() })
+        Some(Message { chunks : v, rd_offset : 0, rd_index : 0, count : 1, size : size, timestamp : Instant::now() })
     }
 
     pub fn write(&mut self, buf : BytesMut, idx : usize, offset : usize) -> Result<usize, io::Error> {
@@ -84,8 +86,13 @@ impl Message {
             Err(io::Error::new(ErrorKind::Other, format!("index {} out of range for chunks : {}", idx, self.chunks.len() )))
         } else {
             let len = buf.len();
+            // a resent chunk replaces the original rather than counting twice
+            match self.chunks[idx].take() {
+                Some((old, _)) => self.size -= old.len(),
+                None => self.count += 1
+            }
+            self.size += len;
             self.chunks[idx] = Some((buf, offset)); 
-            self.count += 1;
             Ok(len)
         }
     }
@@ -127,22 +134,154 @@ impl Read for Message {
 }
 
 
+/// The GELF spec asks that incomplete chunked messages be dropped after 5 seconds
+pub const DEFAULT_CHUNK_TIMEOUT_SECS : u64 = 5;
+pub const DEFAULT_MAX_PENDING_MESSAGES : usize = 1000;
+pub const DEFAULT_MAX_PENDING_BYTES : usize = 64 * 1024 * 1024;
+
 #[derive(Debug)]
 pub struct Parser{
-chunkmap : HashMap<MessageId, Message>
+chunkmap : HashMap<MessageId, Message>,
+          // message ids in the order they were first seen, oldest at the front
+          arrivals : VecDeque<(MessageId, Instant)>,
+          pending_bytes : usize,
+          chunk_timeout : Duration,
+          max_pending : usize,
+          max_pending_bytes : usize,
+          discarded : usize,
+          failed : usize
 }
 
 
 impl Parser {
+    /// A parser with the default limits
+    #[cfg(test)]
     pub fn new() -> Parser {
+        Parser::with_limits(Duration::from_secs(DEFAULT_CHUNK_TIMEOUT_SECS),
+                            DEFAULT_MAX_PENDING_MESSAGES,
+                            DEFAULT_MAX_PENDING_BYTES)
+    }
+
+    /// Creates a parser which discards chunked messages that are not complete within `chunk_timeout`,
+    /// and which never holds more than `max_pending` incomplete messages or `max_pending_bytes` of chunks.
+    pub fn with_limits(chunk_timeout : Duration, max_pending : usize, max_pending_bytes : usize) -> Parser {
         Parser {
-            chunkmap : HashMap::new()
+            chunkmap : HashMap::new(),
+            arrivals : VecDeque::new(),
+            pending_bytes : 0,
+            chunk_timeout : chunk_timeout,
+            max_pending : max_pending,
+            max_pending_bytes : max_pending_bytes,
+            discarded : 0,
+            failed : 0
+        }
+    }
+
+    /// The number of incomplete messages which have been thrown away so far
+    pub fn discarded(&self) -> usize {
+        self.discarded
+    }
+
+    /// The number of complete messages which could not be decoded
+    pub fn failed(&self) -> usize {
+        self.failed
+    }
+
+    /// The number of incomplete messages currently being reassembled
+    #[cfg(test)]
+    pub fn pending(&self) -> usize {
+        self.chunkmap.len()
+    }
+
+    /// Drops arrivals at the front whose message has completed, or whose id was re-used
+    fn prune_arrivals(&mut self) {
+        while let Some(&(id, ts)) = self.arrivals.front() {
+            if self.chunkmap.get(&id).map(|m| m.timestamp == ts).unwrap_or(false) {
+                break;
+            }
+            self.arrivals.pop_front();
+        }
+    }
+
+    fn evict_oldest(&mut self) -> bool {
+        while let Some((id, ts)) = self.arrivals.pop_front() {
+            // ids of messages which completed, or were re-used, are skipped
+            if self.chunkmap.get(&id).map(|m| m.timestamp == ts).unwrap_or(false) {
+                let m = self.chunkmap.remove(&id).unwrap();
+                self.pending_bytes -= m.size;
+                self.discarded += 1;
+                debug!("discarding incomplete message {} with {} of {} chunks", id, m.count, m.chunks.len());
+                return true;
+            }
+        }
+        false
+    }
+
+    fn expire(&mut self, now : Instant) {
+        loop {
+            // only the oldest live message's own arrival decides whether it has expired
+            self.prune_arrivals();
+            match self.arrivals.front() {
+                Some(&(_, ts)) if now.duration_since(ts) > self.chunk_timeout => {},
+                _ => break
+            }
+            if !self.evict_oldest() { break; }
+        }
+    }
+
+    fn add_chunk(&mut self, hdr : &GelfChunkHeader, buf : BytesMut, hdr_sz : usize) -> Option<Message> {
+        // copied out, as fields of the packed header can't be borrowed
+        let id = hdr.id;
+        self.expire(Instant::now());
+
+        if !self.chunkmap.contains_key(&id) {
+            while self.chunkmap.len() >= self.max_pending && self.evict_oldest() {}
+            let m = Message::new(hdr.seq_max);
+            self.arrivals.push_back((id, m.timestamp));
+            self.chunkmap.insert(id, m);
+        }
+        while self.pending_bytes + buf.len() > self.max_pending_bytes {
+            self.prune_arrivals();
+            let oldest = self.arrivals.front().map(|&(first, _)| first);
+            if oldest == Some(id) || !self.evict_oldest() {
+                break;
+            }
+        }
+
+        let complete = {
+            let m = match self.chunkmap.get_mut(&id) {
+                Some(m) => m,
+                None => return None
+            };
+            let before = m.size;
+            if let Err(e) = m.write(buf, hdr.seq_num as usize, hdr_sz) {
+                debug!("dropping chunk for message {} : {}", id, e);
+                return None;
+            }
+            self.pending_bytes = self.pending_bytes + m.size - before;
+            m.full()
+        };
+
+        if self.pending_bytes > self.max_pending_bytes {
+            // this message alone is more than we are willing to hold
+            let m = self.chunkmap.remove(&id).unwrap();
+            self.pending_bytes -= m.size;
+            self.discarded += 1;
+            None
+        } else if complete {
+            self.chunkmap.remove(&id).map(|m| { self.pending_bytes -= m.size; m })
+        } else {
+            None
         }
     }
 
     pub fn parse(&mut self, buf : BytesMut) -> Option<Arc<JValue>> {
-        let complete;
         let hdr_sz = mem::size_of::<GelfChunkHeader>();
+        if buf.len() < hdr_sz {
+            let msg = parse_plain(&buf[..]);
+            if msg.is_none() { self.failed += 1; }
+            return msg;
+        }
         let hdr = unsafe { 
             let hdr : GelfChunkHeader = mem::uninitialized();
             let hdrp = &hdr as *const _ as *mut u8;
@@ -155,22 +294,7 @@ impl Parser {
                     Message::new_with_buf(hdr.seq_max, buf, hdr.seq_num, hdr_sz)
                         .map(|m| gelftype.decompressor(m))
                 } else {
-
-                    { 
-                        let m = self.chunkmap.entry(hdr.id).or_insert_with(|| {
-                                Message::new(hdr.seq_max)
-                                });
-                        m.write(buf, hdr.seq_num as usize, hdr_sz).unwrap();
-                        complete = m.full();
-                    } 
-
-                    if complete {
-                        // should be safe to unwrap, since we can't be here if there is no chunkmap
-                        self.chunkmap.remove(&hdr.id).map(|m| gelftype.decompressor(m))
-                    } else {
-                        None
-                    }
-
+                    self.add_chunk(&hdr, buf, hdr_sz).map(|m| gelftype.decompressor(m))
                 } 
 
             } else { // no header found, so we treat this as just a blob of plaintext bytes
@@ -178,12 +302,20 @@ impl Parser {
                     .map(|m| Box::new(BufReader::with_capacity(2048, m)) as Box<Read> )
             };
 
-        msgreader
-            .and_then(|m| de::from_reader(m).ok())
-            .map(|jv| Arc::new(jv))
+        msgreader.and_then(|m| {
+            match de::from_reader(m) {
+                Ok(jv) => Some(Arc::new(jv)),
+                Err(_) => { self.failed += 1; None }
+            }
+        })
     }
 }
 
+/// Decodes a single uncompressed GELF message, as carried by the TCP transport
+pub fn parse_plain(buf : &[u8]) -> Option<Arc<JValue>> {
+    de::from_slice(buf).ok().map(|jv| Arc::new(jv))
+}
+
 pub struct Encoder;
 
 impl Encoder {
@@ -235,13 +367,15 @@ impl Encoder {
 
 #[cfg(test)]
 mod tests {
-    use super::{Message, Parser, Encoder};
+    use super::{Message, Parser, Encoder, parse_plain};
     use super::GelfChunkHeader;
     use bytes::{BytesMut, Buf, BufMut};
     use std::io::{Read};
     use std::fmt::Write;
     use std::fs::File;
     use std::mem;
+    use std::thread;
+    use std::time::Duration;
     use flate2::read::{GzDecoder};
     use flate2::write::{GzEncoder};
     use serde_json::value::Value as JValue;
@@ -522,6 +656,93 @@ mod tests {
 
     }
 
+    fn set_id(chunk : &mut BytesMut, id : u64) {
+        for i in 0..8 {
+            chunk[2 + i] = (id >> (i * 8)) as u8;
+        }
+    }
+
+#[test]
+    fn parser_expires_incomplete() {
+        let mut f = File::open("tests/8ktest.json").unwrap();
+        let mut data = String::new();
+        f.read_to_string(&mut data).unwrap();
+
+        let mut chunks = Encoder::encode_gz(data.as_bytes(), 1500);
+        let mut p = Parser::with_limits(Duration::from_millis(10), 100, 1024 * 1024);
+        let last = chunks.pop().unwrap();
+        for c in chunks.into_iter() {
+            assert!(p.parse(c).is_none());
+        }
+        assert_eq!(p.pending(), 1);
+        thread::sleep(Duration::from_millis(20));
+        // the final chunk arrives too late, and starts a new incomplete message
+        assert!(p.parse(last).is_none());
+        assert_eq!(p.discarded(), 1);
+        assert_eq!(p.pending(), 1);
+    }
+
+#[test]
+    fn parser_keeps_live_after_completed() {
+        let mut f = File::open("tests/8ktest.json").unwrap();
+        let mut data = String::new();
+        f.read_to_string(&mut data).unwrap();
+
+        let mut p = Parser::with_limits(Duration::from_millis(200), 100, 1024 * 1024);
+        let mut a = Encoder::encode_gz(data.as_bytes(), 1500);
+        for c in a.iter_mut() { set_id(c, 1); }
+        let last = a.pop().unwrap();
+        for c in a.into_iter() {
+            assert!(p.parse(c).is_none());
+        }
+        assert!(p.parse(last).is_some());
+
+        // b starts just before a's arrival expires, and is finished just after
+        thread::sleep(Duration::from_millis(150));
+        let mut b = Encoder::encode_gz(data.as_bytes(), 1500);
+        for c in b.iter_mut() { set_id(c, 2); }
+        let last = b.pop().unwrap();
+        for c in b.into_iter() {
+            assert!(p.parse(c).is_none());
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert!(p.parse(last).is_some());
+        assert_eq!(p.discarded(), 0);
+        assert_eq!(p.pending(), 0);
+    }
+
+#[test]
+    fn parser_caps_pending() {
+        let mut f = File::open("tests/8ktest.json").unwrap();
+        let mut data = String::new();
+        f.read_to_string(&mut data).unwrap();
+
+        let mut p = Parser::with_limits(Duration::from_secs(5), 2, 1024 * 1024);
+        for id in 0..5 {
+            let mut c = Encoder::encode_gz(data.as_bytes(), 1500).remove(0);
+            set_id(&mut c, id);
+            assert!(p.parse(c).is_none());
+        }
+        assert_eq!(p.pending(), 2);
+        assert_eq!(p.discarded(), 3);
+
+        let mut p = Parser::with_limits(Duration::from_secs(5), 100, 2000);
+        for id in 0..3 {
+            let mut c = Encoder::encode_gz(data.as_bytes(), 1500).remove(0);
+            set_id(&mut c, id);
+            assert!(p.parse(c).is_none());
+        }
+        assert_eq!(p.pending(), 1);
+        assert_eq!(p.discarded(), 2);
+    }
+
+#[test]
+    fn parse_plain_frame() {
+        let v = parse_plain(br#"{"version":"1.1","host":"example.org","short_message":"hi"}"#).unwrap();
+        assert_eq!(v["host"].as_str().unwrap(), "example.org");
+        assert!(parse_plain(b"not json").is_none());
+    }
+
 #[bench]
     fn bench_big_multipart_gz(b: &mut Bencher) {
         let mut f = File::open("tests/8ktest.json").unwrap();
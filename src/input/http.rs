@@ -0,0 +1,172 @@
+use std::io::{self, Read};
+use std::error::Error;
+use std::fmt;
+use std::thread;
+use hyper::server::{Server, Request, Response, Handler};
+use hyper::status::StatusCode;
+use hyper::method::Method;
+use hyper::uri::RequestUri;
+use hyper::header::{ContentEncoding, Encoding};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use serde_json::de;
+use std::sync::Arc;
+use route::{Input, SharedRoute};
+use metrics::InputMetrics;
+
+// refuse anything larger than this once decompressed, regardless of what Content-Length claims
+const MAX_BODY_SIZE : u64 = 16 * 1024 * 1024;
+
+/// What reading more than `MAX_BODY_SIZE` bytes of a request fails with, so it can be answered with a 413
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "body is larger than {} bytes", MAX_BODY_SIZE)
+    }
+}
+
+impl Error for TooLarge {
+    fn description(&self) -> &str { "body too large" }
+}
+
+/// Fails once more than `MAX_BODY_SIZE` bytes have been read from `inner`
+struct Capped<R> {
+    inner : R,
+    read : u64
+}
+
+impl<R : Read> Read for Capped<R> {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.read > MAX_BODY_SIZE {
+            Err(io::Error::new(io::ErrorKind::InvalidData, TooLarge))
+        } else {
+            Ok(n)
+        }
+    }
+}
+
+/// A 413 for a body which went over the limit, before or after decompression, and a 400 for any other bad body
+fn status_for(e : &io::Error) -> StatusCode {
+    if e.get_ref().map_or(false, |e| e.is::<TooLarge>()) {
+        StatusCode::PayloadTooLarge
+    } else {
+        debug!("Rejecting unreadable GELF body : {}", e);
+        StatusCode::BadRequest
+    }
+}
+
+struct GelfHandler {
+    route : SharedRoute,
+    metrics : InputMetrics
+}
+
+impl GelfHandler {
+
+    fn decoder<'a, R : Read + 'a>(encoding : Option<&ContentEncoding>, body : R) -> Result<Box<Read + 'a>, StatusCode> {
+        let body = Capped { inner : body, read : 0 };
+        match encoding.and_then(|e| e.last()) {
+            None | Some(&Encoding::Identity) => Ok(Box::new(body) as Box<Read + 'a>),
+            Some(&Encoding::Gzip) => GzDecoder::new(body).map(|d| Box::new(d) as Box<Read + 'a>).map_err(|e| status_for(&e)),
+            Some(&Encoding::Deflate) => Ok(Box::new(ZlibDecoder::new(body)) as Box<Read + 'a>),
+            Some(e) => { debug!("Rejecting GELF body with unsupported Content-Encoding {}", e); Err(StatusCode::UnsupportedMediaType) }
+        }
+    }
+
+    /// Reads the decoded body, up to one byte past the limit so an oversized body can be told apart
+    fn read_body<R : Read>(body : R) -> Result<Vec<u8>, StatusCode> {
+        let mut buf = Vec::new();
+        match body.take(MAX_BODY_SIZE + 1).read_to_end(&mut buf) {
+            Ok(n) if n as u64 > MAX_BODY_SIZE => Err(StatusCode::PayloadTooLarge),
+            Ok(_) => Ok(buf),
+            Err(e) => Err(status_for(&e))
+        }
+    }
+}
+
+impl Handler for GelfHandler {
+    fn handle(&self, mut req : Request, mut res : Response) {
+        let is_gelf = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path == "/gelf" || path.starts_with("/gelf?"),
+            _ => false
+        };
+        let status = if !is_gelf {
+            StatusCode::NotFound
+        } else if req.method != Method::Post {
+            StatusCode::MethodNotAllowed
+        } else {
+            self.metrics.received.inc();
+            let encoding = req.headers.get::<ContentEncoding>().cloned();
+            match Self::decoder(encoding.as_ref(), &mut req).and_then(Self::read_body) {
+                Ok(buf) => match de::from_slice(&buf) {
+                    Ok(msg) => {
+                        match *self.route.lock().unwrap() {
+                            Some(ref r) => { r.dispatch(Arc::new(msg)); StatusCode::Accepted },
+                            // we are shutting down
+                            None => StatusCode::ServiceUnavailable
+                        }
+                    },
+                    Err(e) => {
+                        debug!("Rejecting malformed GELF body : {}", e);
+                        self.metrics.parse_failures.inc();
+                        StatusCode::BadRequest
+                    }
+                },
+                Err(status) => { self.metrics.parse_failures.inc(); status }
+            }
+        };
+        *res.status_mut() = status;
+    }
+}
+
+pub fn spawn(input : Input, route : SharedRoute) -> io::Result<()> {
+    let handler = GelfHandler { route : route, metrics : InputMetrics::new(&input.name) };
+    let server = Server::http(input.addr).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    println!("Listening for GELF over HTTP on {}", input.addr);
+    thread::spawn(move || {
+        match server.handle(handler) {
+            // dropping `Listening` waits on the server threads
+            Ok(listening) => drop(listening),
+            Err(e) => error!("HTTP input {} failed : {}", input.name, e)
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GelfHandler, MAX_BODY_SIZE};
+    use hyper::header::{ContentEncoding, Encoding};
+    use hyper::status::StatusCode;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    fn gzip(body : &[u8], level : Compression) -> Vec<u8> {
+        let mut e = GzEncoder::new(Vec::new(), level);
+        e.write_all(body).unwrap();
+        e.finish().unwrap()
+    }
+
+    fn status(body : &[u8]) -> Result<usize, StatusCode> {
+        let gz = ContentEncoding(vec![Encoding::Gzip]);
+        GelfHandler::decoder(Some(&gz), body).and_then(GelfHandler::read_body).map(|b| b.len())
+    }
+
+#[test]
+    fn http_body_limit() {
+        let small = gzip(br#"{"short_message":"hi"}"#, Compression::Default);
+        assert_eq!(status(&small), Ok(22));
+        assert_eq!(status(&small[..small.len() - 12]), Err(StatusCode::BadRequest));
+
+        let big = vec![b'x'; MAX_BODY_SIZE as usize + 1];
+        // too large only once decompressed
+        let bomb = gzip(&big, Compression::Best);
+        assert!((bomb.len() as u64) < MAX_BODY_SIZE);
+        assert_eq!(status(&bomb), Err(StatusCode::PayloadTooLarge));
+        // too large before it is decompressed
+        assert_eq!(status(&gzip(&big, Compression::None)), Err(StatusCode::PayloadTooLarge));
+    }
+}
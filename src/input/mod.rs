@@ -0,0 +1,23 @@
+pub mod udp;
+pub mod tcp;
+pub mod http;
+
+use std::io;
+use futures::sync::oneshot;
+use tokio_core::reactor::Handle;
+use route::{Input, Protocol, SharedRoute};
+
+/// A running input.  Dropping it closes the input's socket, except for HTTP inputs whose
+/// blocking server cannot be stopped; those answer 503 once their route has been emptied.
+pub struct Listener {
+    _stop : Option<oneshot::Sender<()>>
+}
+
+pub fn spawn(input : Input, route : SharedRoute, handle : &Handle) -> io::Result<Listener> {
+    let stop = match input.protocol {
+        Protocol::Udp => Some(udp::spawn(input, route, handle)?),
+        Protocol::Tcp => Some(tcp::spawn(input, route, handle)?),
+        Protocol::Http => { http::spawn(input, route)?; None }
+    };
+    Ok(Listener { _stop : stop })
+}
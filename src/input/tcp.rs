@@ -0,0 +1,111 @@
+use std::io;
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use tokio_core::io::{Io, Codec, EasyBuf};
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Handle;
+use route::{self, Input, SharedRoute};
+use gelf;
+use metrics::InputMetrics;
+
+/// The largest frame accepted before the sender is hung up on, so a client which never sends a null
+/// byte can't grow the buffer without bound
+const MAX_FRAME_SIZE : usize = 16 * 1024 * 1024;
+
+/// GELF over TCP carries one uncompressed JSON document per frame,
+/// with each frame terminated by a null byte.
+struct NullDelimitedCodec;
+
+fn frame_too_large(len : usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is larger than the maximum of {}", len, MAX_FRAME_SIZE))
+}
+
+impl Codec for NullDelimitedCodec {
+    type In = EasyBuf;
+    type Out = Vec<u8>;
+
+    fn decode(&mut self, buf: &mut EasyBuf) -> Result<Option<Self::In>, io::Error> {
+        match buf.as_slice().iter().position(|b| *b == 0) {
+            Some(i) if i > MAX_FRAME_SIZE => Err(frame_too_large(i)),
+            Some(i) => {
+                let frame = buf.drain_to(i);
+                buf.drain_to(1);
+                Ok(Some(frame))
+            },
+            None if buf.len() > MAX_FRAME_SIZE => Err(frame_too_large(buf.len())),
+            None => Ok(None)
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut EasyBuf) -> Result<Self::In, io::Error> {
+        // some senders don't terminate the last message before closing the socket
+        if let Some(frame) = self.decode(buf)? {
+            Ok(frame)
+        } else {
+            let len = buf.len();
+            Ok(buf.drain_to(len))
+        }
+    }
+
+    fn encode(&mut self, msg: Self::Out, into: &mut Vec<u8>) -> Result<(), io::Error> {
+        into.extend_from_slice(&msg[..]);
+        into.push(0);
+        Ok(())
+    }
+}
+
+/// Accepts connections on the input's address until the returned sender is fired or dropped
+pub fn spawn(input : Input, route : SharedRoute, handle : &Handle) -> io::Result<oneshot::Sender<()>> {
+    let listener = TcpListener::bind(&input.addr, handle)?;
+    println!("Listening for GELF over TCP on {}", input.addr);
+    let conn_handle = handle.clone();
+    let name = input.name.clone();
+    let metrics = InputMetrics::new(&input.name);
+    let srv = listener.incoming().for_each(move |(sock, addr)| {
+        trace!("accepted GELF connection from {}", addr);
+        let route = route.clone();
+        let metrics = metrics.clone();
+        let conn = sock.framed(NullDelimitedCodec)
+            .for_each(move |frame| {
+                if frame.len() > 0 {
+                    metrics.received.inc();
+                    match gelf::parse_plain(frame.as_slice()) {
+                        Some(msg) => if !route::dispatch(&route, msg) {
+                            // the input has been retired, so hang up on the sender
+                            return Err(io::Error::new(io::ErrorKind::Other, "input has been removed"));
+                        },
+                        None => metrics.parse_failures.inc()
+                    }
+                }
+                Ok(())
+            }).map_err(move |e| error!("GELF connection from {} failed : {}", addr, e));
+        conn_handle.spawn(conn);
+        Ok(())
+    }).map_err(move |e| error!("TCP input {} failed : {}", name, e));
+    let (stop, stopped) = oneshot::channel();
+    handle.spawn(srv.select(stopped.then(|_| Ok(()))).map(|_| ()).map_err(|_| ()));
+    Ok(stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+#[test]
+    fn tcp_frame_size_is_capped() {
+        let mut codec = NullDelimitedCodec;
+        let mut buf = EasyBuf::from(b"{\"a\":1}\0{\"b\"".to_vec());
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().as_slice(), b"{\"a\":1}");
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(codec.decode_eof(&mut buf).unwrap().as_slice(), b"{\"b\"");
+
+        let mut buf = EasyBuf::from(vec![b'x'; MAX_FRAME_SIZE]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.get_mut().push(b'x');
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut buf = EasyBuf::from(vec![b'x'; MAX_FRAME_SIZE + 1]);
+        buf.get_mut().push(0);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+}
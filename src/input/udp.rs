@@ -0,0 +1,53 @@
+use std::io;
+use std::net::SocketAddr;
+use futures::{Future, Stream};
+use futures::sync::oneshot;
+use tokio_core::net::{UdpSocket, UdpCodec};
+use tokio_core::reactor::Handle;
+use bytes::BytesMut;
+use route::{self, Input, SharedRoute};
+use gelf;
+use metrics::InputMetrics;
+
+struct BytesMutCodec;
+
+impl UdpCodec for BytesMutCodec {
+    type In = (SocketAddr, BytesMut);
+    type Out = (SocketAddr, BytesMut);
+
+    fn decode(&mut self, addr : &SocketAddr, buf: &[u8]) -> Result<Self::In, io::Error> {
+        Ok((*addr, BytesMut::from(buf)))
+    }
+    
+    fn encode(&mut self, item: Self::Out, into: &mut Vec<u8>) -> SocketAddr {
+        into.extend_from_slice(&item.1[..]);
+        into.push('\n' as u8);
+        item.0
+    }
+}
+
+/// Listens on the input's address until the returned sender is fired or dropped
+pub fn spawn(input : Input, route : SharedRoute, handle : &Handle) -> io::Result<oneshot::Sender<()>> {
+    let sock = UdpSocket::bind(&input.addr, handle)?;
+    println!("Listening for GELF over UDP on {}", input.addr);
+    let mut parser = gelf::Parser::with_limits(input.chunk_timeout,
+                                               input.max_pending_messages,
+                                               input.max_pending_bytes);
+    let name = input.name.clone();
+    let metrics = InputMetrics::new(&input.name);
+    let srv = sock.framed(BytesMutCodec)
+        .filter_map(move |(_addr, buf)| {
+            let (failed, discarded) = (parser.failed(), parser.discarded());
+            metrics.received.inc();
+            let msg = parser.parse(buf);
+            metrics.parse_failures.add(parser.failed() - failed);
+            metrics.chunks_discarded.add(parser.discarded() - discarded);
+            msg
+        }).for_each(move |msg| {
+            route::dispatch(&route, msg);
+            Ok(())
+        }).map_err(move |e| error!("UDP input {} failed : {}", name, e));
+    let (stop, stopped) = oneshot::channel();
+    handle.spawn(srv.select(stopped.then(|_| Ok(()))).map(|_| ()).map_err(|_| ()));
+    Ok(stop)
+}
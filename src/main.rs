@@ -3,6 +3,7 @@
 
 #[macro_use] extern crate log;
 #[macro_use] extern crate serde_derive;
+#[macro_use] extern crate lazy_static;
 
 extern crate futures;
 extern crate tokio_core;
@@ -10,12 +11,13 @@ extern crate flate2;
 extern crate byteorder;
 //extern crate block_allocator;
 //extern crate block_alloc_appendbuf;
+extern crate serde;
 extern crate serde_json;
 extern crate toml;
 extern crate time;
 extern crate bytes;
-extern crate rusoto;
-extern crate rs_es;
+extern crate rusoto_core;
+extern crate rusoto_s3;
 extern crate chrono;
 extern crate md5;
 extern crate rustc_serialize;
@@ -25,113 +27,92 @@ extern crate snap;
 extern crate nix;
 extern crate postgres;
 extern crate csv;
+extern crate hyper;
+extern crate hyper_openssl;
+extern crate openssl;
+extern crate regex;
+extern crate zstd;
+#[cfg(test)] extern crate rand;
 
 mod gelf;
+mod config;
+mod filter;
 mod route;
+mod input;
 mod output;
+mod file_queue;
+mod signals;
+mod metrics;
+mod router;
 
 pub use gelf::Encoder;
 
 use std::str;
-use std::io;
-use std::net::SocketAddr;
-use std::fs::File;
 use std::process;
 use std::env;
-use std::io::{Read};
-use futures::stream::{self, Stream};
-use route::{Input, Route};
-use route::Filter;
-use std::sync::mpsc::TrySendError;
-use tokio_core::net::{UdpSocket, UdpCodec};
-use tokio_core::reactor::{Core};
-use bytes::{BytesMut, BufMut};
-use std::rc::Rc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+use futures::Stream;
+use config::Config;
+use router::Router;
+use tokio_core::reactor::{Core, Interval};
 
-struct BytesMutCodec;
-
-impl UdpCodec for BytesMutCodec {
-    type In = (SocketAddr, BytesMut);
-    type Out = (SocketAddr, BytesMut);
-
-    fn decode(&mut self, addr : &SocketAddr, buf: &[u8]) -> Result<Self::In, io::Error> {
-        Ok((*addr, BytesMut::from(buf)))
-    }
-    
-    fn encode(&mut self, item: Self::Out, into: &mut Vec<u8>) -> SocketAddr {
-        into.extend_from_slice(&item.1[..]);
-        into.push('\n' as u8);
-        item.0
-    }
+fn load_config(path : &str) -> Result<Config, ()> {
+    trace!("reading {}", path);
+    Config::load(path).map_err(|errors| {
+        let mut stderr = io::stderr();
+        for e in errors.iter() {
+            let _ = writeln!(stderr, "{} : {}", path, e);
+        }
+    })
 }
 
-
 fn main() {
     drop(env_logger::init());
-    let mut core = Core::new().unwrap();
     let a : Vec<String> = env::args().collect();
-    if a.len() < 2 {
-        println!("USAGE : lout <configfilepath>");
+    if a.len() == 3 && a[1] == "check" {
+        match load_config(&a[2]) {
+            Ok(_) => { println!("{} is valid", a[2]); process::exit(0); },
+            Err(_) => process::exit(1)
+        }
+    }
+    if a.len() != 2 {
+        println!("USAGE : lout [check] <configfilepath>");
         process::exit(-1);
     }
-    let mut configstr = String::new();
-    trace!("reading {}", a[1]);
-    File::open(a[1].to_string()).unwrap().read_to_string(&mut configstr).unwrap();
-    let mut tp = toml::Parser::new(&configstr);
-    let config = if let Some(c) = tp.parse() {
-        c
-    } else {
-        panic!("Error loading config : {:#?}", tp.errors);
+    let config = match load_config(&a[1]) {
+        Ok(c) => c,
+        Err(_) => process::exit(-1)
     };
 
-    if !config.contains_key("input") || !config.contains_key("output") || !config.contains_key("route") {
-        error!("Config file should contain [input], [output] and [route] sections");
-        process::exit(-1);
+    signals::install();
+
+    if let Some(ref m) = config.metrics {
+        metrics::serve(config::resolve(&m.url).unwrap());
     }
 
-    let failcount = Rc::new(AtomicUsize::new(0));
+    let mut core = Core::new().unwrap();
     let handle = core.handle().clone(); 
-    let routes = Route::with_config(config);
-    let inputs : Vec<Result<Route, io::Error>> = routes.into_iter().map(|(_, v)| Ok(v)).collect();
-    //let inputs : Vec<Route> = routes.into_iter().map(|(k, v)| v).collect();
+    let mut router = Router::start(&a[1], config, &handle);
 
-    let instream =
-    stream::iter(inputs.into_iter()).map(|route| {
-        let input : Input = route.get_input();
-        let sock = UdpSocket::bind(&input.addr, &handle).unwrap();
-        (sock.framed(BytesMutCodec),  route)
-    }).and_then(|(stream, route)| {
-        let fca = failcount.clone();
-        let mut parser = gelf::Parser::new();
-        stream.filter_map(move |(_addr, buf)| {
-            parser.parse(buf)
-        }).for_each(move |msg| {
-            for o in route.get_outputs().iter() {
-                let mut write = false;
-                if let Some(Filter::IfHasField(ref field)) = o.filter {
-                    if msg.pointer(field).is_some() {
-                        write = true;
-                    }
-                } else {
-                    write = true;
-                }
-                if write {
-                    match o.channel.try_send(msg.clone()) {
-                        Ok(()) => () ,
-                        Err(TrySendError::Full(_)) => {
-                            let fc = fca.load(Ordering::Relaxed) + 1;
-                            fca.store(fc, Ordering::Relaxed);
-                            if fc % 100 == 0 { println!("Failed to send to output {}, buffer is full", o.output_name) };},
-                        Err(TrySendError::Disconnected(_)) => panic!("Downstream reader has failed for {}", o.output_name)
-                    };
-                }
-            }
-            Ok(())
-        })
-    }).for_each(|_| Ok(()));
-   
+    let ticks = Interval::new(Duration::from_millis(100), &handle).unwrap();
+    core.run(ticks.take_while(|_| Ok(!signals::shutdown_requested())).for_each(|_| {
+        router.tick(signals::reload_requested(), &handle);
+        Ok(())
+    })).map_err(|_| "ack!").unwrap();
 
-    core.run(instream).map_err(|_| "ack!").unwrap();
-}
+    let shutdown_secs = router.config().shutdown_timeout_secs;
+
+    println!("Shutting down, waiting up to {} seconds for outputs to flush", shutdown_secs);
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs(shutdown_secs));
+        error!("Outputs did not finish within {} seconds, exiting anyway", shutdown_secs);
+        process::exit(1);
+    });
 
+    // stop the inputs, then release the output channels so each worker flushes its batch and exits
+    drop(core);
+    router.shutdown();
+    println!("Shutdown complete");
+}
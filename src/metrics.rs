@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, AtomicIsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use hyper::server::{Server, Request, Response, Handler};
+use hyper::status::StatusCode;
+use hyper::method::Method;
+use hyper::uri::RequestUri;
+use hyper::header::ContentType;
+
+#[derive(Clone)]
+pub struct Counter(Arc<AtomicUsize>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n : usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+#[derive(Clone)]
+pub struct Gauge(Arc<AtomicIsize>);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, v : isize) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+}
+
+/// A running count and sum of observations, exported as a prometheus summary without quantiles
+#[derive(Clone)]
+pub struct Summary(Arc<Mutex<(u64, f64)>>);
+
+impl Summary {
+    pub fn observe(&self, v : f64) {
+        let mut s = self.0.lock().unwrap();
+        s.0 += 1;
+        s.1 += v;
+    }
+
+    pub fn observe_duration(&self, d : Duration) {
+        self.observe(d.as_secs() as f64 + d.subsec_nanos() as f64 / 1_000_000_000.0);
+    }
+}
+
+enum Series {
+    Counter(Counter),
+    Gauge(Gauge),
+    Summary(Summary)
+}
+
+struct Family {
+    help : &'static str,
+    kind : &'static str,
+    series : BTreeMap<String, Series>
+}
+
+lazy_static! {
+    static ref REGISTRY : Mutex<BTreeMap<&'static str, Family>> = Mutex::new(BTreeMap::new());
+}
+
+fn format_labels(labels : &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs : Vec<String> = labels.iter().map(|&(k, v)| {
+        format!("{}=\"{}\"", k, v.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n"))
+    }).collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Finds the series for `name` and `labels`, registering it with `make` the first time it is asked for.
+/// The same labels always get back the same series, so a reader and writer on different threads can
+/// each look it up.
+fn register<F>(name : &'static str, help : &'static str, kind : &'static str, labels : &[(&str, &str)], make : F) -> Series
+    where F : FnOnce() -> Series
+{
+    let mut reg = REGISTRY.lock().unwrap();
+    let family = reg.entry(name).or_insert_with(|| Family { help : help, kind : kind, series : BTreeMap::new() });
+    let s = family.series.entry(format_labels(labels)).or_insert_with(make);
+    match *s {
+        Series::Counter(ref c) => Series::Counter(c.clone()),
+        Series::Gauge(ref g) => Series::Gauge(g.clone()),
+        Series::Summary(ref s) => Series::Summary(s.clone())
+    }
+}
+
+pub fn counter(name : &'static str, help : &'static str, labels : &[(&str, &str)]) -> Counter {
+    match register(name, help, "counter", labels, || Series::Counter(Counter(Arc::new(AtomicUsize::new(0))))) {
+        Series::Counter(c) => c,
+        _ => panic!("metric {} is not a counter", name)
+    }
+}
+
+pub fn gauge(name : &'static str, help : &'static str, labels : &[(&str, &str)]) -> Gauge {
+    match register(name, help, "gauge", labels, || Series::Gauge(Gauge(Arc::new(AtomicIsize::new(0))))) {
+        Series::Gauge(g) => g,
+        _ => panic!("metric {} is not a gauge", name)
+    }
+}
+
+pub fn summary(name : &'static str, help : &'static str, labels : &[(&str, &str)]) -> Summary {
+    match register(name, help, "summary", labels, || Series::Summary(Summary(Arc::new(Mutex::new((0, 0.0)))))) {
+        Series::Summary(s) => s,
+        _ => panic!("metric {} is not a summary", name)
+    }
+}
+
+/// The metrics reported by every input
+#[derive(Clone)]
+pub struct InputMetrics {
+    pub received : Counter,
+    pub parse_failures : Counter,
+    pub chunks_discarded : Counter
+}
+
+impl InputMetrics {
+    pub fn new(input : &str) -> InputMetrics {
+        let l = [("input", input)];
+        InputMetrics {
+            received : counter("lout_input_received_total", "Datagrams, frames or requests received", &l),
+            parse_failures : counter("lout_input_parse_failures_total", "Messages which could not be decoded", &l),
+            chunks_discarded : counter("lout_input_chunks_discarded_total", "Incomplete chunked messages which were evicted", &l)
+        }
+    }
+}
+
+/// The metrics reported by every output worker
+#[derive(Clone)]
+pub struct OutputMetrics {
+    pub depth : Gauge,
+    pub batch_size : Summary,
+    pub batch_seconds : Summary,
+    pub errors : Counter
+}
+
+impl OutputMetrics {
+    pub fn new(output : &str) -> OutputMetrics {
+        let l = [("output", output)];
+        OutputMetrics {
+            depth : gauge("lout_output_queue_depth", "Messages waiting in memory for the output", &l),
+            batch_size : summary("lout_output_batch_size", "Messages per batch sent upstream", &l),
+            batch_seconds : summary("lout_output_batch_seconds", "Time taken to send a batch upstream", &l),
+            errors : counter("lout_output_errors_total", "Errors returned by the upstream sink", &l)
+        }
+    }
+}
+
+/// Renders every registered metric in the prometheus text format
+pub fn render() -> String {
+    let reg = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (name, family) in reg.iter() {
+        let _ = writeln!(out, "# HELP {} {}", name, family.help);
+        let _ = writeln!(out, "# TYPE {} {}", name, family.kind);
+        for (labels, series) in family.series.iter() {
+            match *series {
+                Series::Counter(ref c) => { let _ = writeln!(out, "{}{} {}", name, labels, c.0.load(Ordering::Relaxed)); },
+                Series::Gauge(ref g) => { let _ = writeln!(out, "{}{} {}", name, labels, g.0.load(Ordering::Relaxed)); },
+                Series::Summary(ref s) => {
+                    let s = s.0.lock().unwrap();
+                    let _ = writeln!(out, "{}_sum{} {}", name, labels, s.1);
+                    let _ = writeln!(out, "{}_count{} {}", name, labels, s.0);
+                }
+            }
+        }
+    }
+    out
+}
+
+struct MetricsHandler;
+
+impl Handler for MetricsHandler {
+    fn handle(&self, req : Request, mut res : Response) {
+        let is_metrics = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path == "/metrics",
+            _ => false
+        };
+        if is_metrics && req.method == Method::Get {
+            res.headers_mut().set(ContentType("text/plain; version=0.0.4".parse().unwrap()));
+            let _ = res.send(render().as_bytes());
+        } else {
+            *res.status_mut() = StatusCode::NotFound;
+        }
+    }
+}
+
+/// Serves `GET /metrics` on `addr` from a background thread
+pub fn serve(addr : SocketAddr) {
+    let server = Server::http(addr).unwrap();
+    println!("Serving metrics on http://{}/metrics", addr);
+    thread::spawn(move || {
+        match server.handle(MetricsHandler) {
+            // dropping `Listening` waits on the server threads
+            Ok(listening) => drop(listening),
+            Err(e) => error!("Metrics endpoint failed : {}", e)
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{counter, gauge, summary, render};
+
+#[test]
+    fn metrics_render() {
+        let c = counter("lout_test_total", "a test counter", &[("input", "a\"b")]);
+        c.inc();
+        counter("lout_test_total", "a test counter", &[("input", "a\"b")]).add(2);
+        gauge("lout_test_depth", "a test gauge", &[]).set(7);
+        summary("lout_test_seconds", "a test summary", &[("output", "o")]).observe(1.5);
+        let out = render();
+        assert!(out.contains("# TYPE lout_test_total counter\n"));
+        assert!(out.contains("lout_test_total{input=\"a\\\"b\"} 3\n"));
+        assert!(out.contains("lout_test_depth 7\n"));
+        assert!(out.contains("lout_test_seconds_sum{output=\"o\"} 1.5\n"));
+        assert!(out.contains("lout_test_seconds_count{output=\"o\"} 1\n"));
+    }
+}
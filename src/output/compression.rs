@@ -0,0 +1,93 @@
+use std::io::{self, Read, Write};
+use flate2::write::GzEncoder;
+use flate2::Compression as GzLevel;
+use snap;
+use zstd;
+
+// zstd's own default, which compresses about as well as gzip while being several times faster
+const ZSTD_LEVEL : i32 = 3;
+
+/// A codec for batches which are uploaded as files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Snappy,
+    Zstd
+}
+
+impl Compression {
+    /// Parses a `compression` option, where "none" means batches are sent as they are
+    pub fn parse(c : &str) -> Result<Option<Compression>, String> {
+        match c {
+            "none" => Ok(None),
+            "gzip" => Ok(Some(Compression::Gzip)),
+            "snappy" => Ok(Some(Compression::Snappy)),
+            "zstd" => Ok(Some(Compression::Zstd)),
+            c => Err(format!("compression {} should be one of gzip, snappy, zstd or none", c))
+        }
+    }
+
+    /// Compresses everything read from `src` into `dst`, a buffer at a time, returning `dst`
+    pub fn compress<R : Read, W : Write>(&self, mut src : R, dst : W) -> io::Result<W> {
+        match *self {
+            Compression::Gzip => {
+                let mut gze = GzEncoder::new(dst, GzLevel::Default);
+                io::copy(&mut src, &mut gze)?;
+                gze.finish()
+            },
+            Compression::Snappy => {
+                let mut w = snap::Writer::new(dst);
+                io::copy(&mut src, &mut w)?;
+                w.into_inner().map_err(|_| io::Error::new(io::ErrorKind::Other, "snappy compression failed"))
+            },
+            Compression::Zstd => {
+                let mut zse = zstd::stream::Encoder::new(dst, ZSTD_LEVEL)?;
+                io::copy(&mut src, &mut zse)?;
+                zse.finish()
+            }
+        }
+    }
+
+    pub fn content_encoding(&self) -> &'static str {
+        match *self {
+            Compression::Gzip => "gzip",
+            Compression::Snappy => "x-snappy-framed",
+            Compression::Zstd => "zstd"
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Compression::Gzip => ".gz",
+            Compression::Snappy => ".sz",
+            Compression::Zstd => ".zst"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compression;
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+    use snap;
+    use zstd;
+
+#[test]
+    fn compression_round_trip() {
+        let data = b"{\"short_message\":\"hello\"}\n".iter().cycle().take(10000).cloned().collect::<Vec<u8>>();
+        for c in [Compression::Gzip, Compression::Snappy, Compression::Zstd].iter() {
+            let compressed = c.compress(&data[..], Vec::new()).unwrap();
+            assert!(compressed.len() < data.len() / 10, "{:?} compressed to {}", c, compressed.len());
+            let mut out = Vec::new();
+            match *c {
+                Compression::Gzip => GzDecoder::new(&compressed[..]).unwrap().read_to_end(&mut out).unwrap(),
+                Compression::Snappy => snap::Reader::new(&compressed[..]).read_to_end(&mut out).unwrap(),
+                Compression::Zstd => zstd::stream::Decoder::new(&compressed[..]).unwrap().read_to_end(&mut out).unwrap()
+            };
+            assert_eq!(out, data);
+        }
+        assert_eq!(Compression::parse("none"), Ok(None));
+        assert!(Compression::parse("lz4").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::thread::{self, JoinHandle};
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver, TrySendError, TryRecvError, RecvTimeoutError};
+use std::time::Duration;
+use std::path::PathBuf;
+use serde_json::Value as JValue;
+use serde_json::{ser, de};
+use file_queue::{FileQueue, QueueConfig, Fsync};
+use metrics::{self, OutputMetrics};
+use config::BufferConfig;
+
+// how many messages the worker may hold in memory ahead of the disk queue
+pub const WORKER_CHANNEL_SIZE : usize = 1000;
+pub const DEFAULT_BUFFER_MAX : usize = 10000;
+
+/// Reads the `buffer_*` options for an output which has `buffer = "disk"`
+fn queue_config(name : &str, cfg : &BufferConfig) -> (PathBuf, QueueConfig) {
+    let path = cfg.buffer_path.clone().unwrap_or(format!("/var/lib/lout/{}.queue", name));
+    let defaults = QueueConfig::default();
+    let max_bytes = cfg.buffer_max_bytes.unwrap_or(defaults.max_bytes);
+    let segment_bytes = cfg.buffer_segment_bytes.unwrap_or(defaults.segment_bytes);
+    let fsync_ms = cfg.buffer_fsync_ms.unwrap_or(1000);
+    let fsync = match cfg.buffer_fsync.as_ref().map(|f| f.as_str()) {
+        Some("always") => Fsync::Always,
+        Some("never") => Fsync::Never,
+        None | Some("interval") => Fsync::Interval(Duration::from_millis(fsync_ms)),
+        Some(f) => panic!("{} is not a valid buffer_fsync policy for output {}", f, name)
+    };
+    (PathBuf::from(path), QueueConfig { max_bytes : max_bytes, segment_bytes : segment_bytes, fsync : fsync })
+}
+
+/// Places an on-disk queue in front of an output worker.  Routes send to the returned channel,
+/// and everything they send is written to disk before being handed on to the worker, so a
+/// stalled sink backs up onto disk rather than dropping messages.
+pub fn spawn(name : String, cfg : &BufferConfig, bufmax : usize,
+             worker : (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>)) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
+    let (path, qcfg) = queue_config(&name, cfg);
+    let queue = match FileQueue::open(&path, qcfg) {
+        Ok(q) => q,
+        Err(e) => panic!("Failed to open disk buffer for output {} at {:?} : {}", name, path, e)
+    };
+    println!("Buffering output {} on disk at {:?}", name, path);
+
+    let (worker_handle, worker_tx) = worker;
+    let (tx, rx) = sync_channel(bufmax);
+    let handle = thread::Builder::new().name(format!("{}-buffer", name)).spawn(move || {
+        run(name, queue, rx, worker_tx);
+        // we hold the only other reference once the routes are gone
+        if let Ok(h) = Arc::try_unwrap(worker_handle) {
+            let _ = h.join();
+        }
+    }).unwrap();
+    (Arc::new(handle), tx)
+}
+
+fn run(name : String, mut queue : FileQueue, rx : Receiver<Arc<JValue>>, worker_tx : SyncSender<Arc<JValue>>) {
+    let mut running = true;
+    let mut dropped = 0_u64;
+    // messages handed to the worker stay counted in the output's depth until it takes them
+    let om = OutputMetrics::new(&name);
+    let disk_bytes = metrics::gauge("lout_output_disk_buffer_bytes", "Bytes held in an output's disk buffer", &[("output", &name)]);
+    let disk_dropped = metrics::counter("lout_output_disk_buffer_dropped_total", "Messages dropped because the disk buffer was full", &[("output", &name)]);
+
+    while running {
+        // keep the worker fed from the head of the queue
+        let mut worker_full = false;
+        loop {
+            let msg = match queue.peek() {
+                Ok(Some(buf)) => de::from_slice::<JValue>(buf).ok(),
+                Ok(None) => break,
+                Err(e) => { error!("Failed to read disk buffer for {} : {}", name, e); break; }
+            };
+            match msg {
+                Some(m) => match worker_tx.try_send(Arc::new(m)) {
+                    Ok(()) => om.depth.inc(),
+                    Err(TrySendError::Full(_)) => { worker_full = true; break; },
+                    Err(TrySendError::Disconnected(_)) => {
+                        error!("Output {} has stopped, leaving its backlog on disk", name);
+                        return;
+                    }
+                },
+                None => error!("Discarding corrupt record in disk buffer for {}", name)
+            }
+            if let Err(e) = queue.pop() {
+                error!("Failed to advance disk buffer for {} : {}", name, e);
+                break;
+            }
+        }
+
+        let to = if worker_full { Duration::from_millis(10) } else { Duration::from_millis(100) };
+        let mut next = rx.recv_timeout(to);
+        loop {
+            match next {
+                Ok(msg) => {
+                    om.depth.dec();
+                    let data = ser::to_vec(&*msg).unwrap_or(Vec::new());
+                    if let Err(e) = queue.push(&data) {
+                        dropped += 1;
+                        disk_dropped.inc();
+                        if dropped % 100 == 1 { error!("Failed to buffer message for {} : {}", name, e); }
+                    }
+                },
+                Err(RecvTimeoutError::Disconnected) => { running = false; error!("Main loop channel disconnected. Shutting down {} buffer.", name); break; },
+                Err(RecvTimeoutError::Timeout) => break
+            }
+            // take whatever else is waiting before going back to feeding the worker
+            next = rx.try_recv().map_err(|e| match e {
+                TryRecvError::Empty => RecvTimeoutError::Timeout,
+                TryRecvError::Disconnected => RecvTimeoutError::Disconnected
+            });
+        }
+        disk_bytes.set(queue.disk_bytes() as isize);
+        if let Err(e) = queue.tick() {
+            error!("Failed to sync disk buffer for {} : {}", name, e);
+        }
+    }
+}
@@ -1,153 +1,381 @@
-
-use toml::{Table, Value};
 use std::thread::{self, JoinHandle};
-use std::sync::{Arc, Once, ONCE_INIT }; 
+use std::sync::Arc;
 use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
-use serde_json::Value as JValue;
-use rs_es::Client;
-use rs_es::operations::bulk::Action;
-use rs_es::error::EsError;
+use serde_json::{self, Value as JValue};
 use std::time::{Duration, Instant};
 use std::sync::mpsc::RecvTimeoutError;
 use std::env;
+use std::cmp;
+use std::mem;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::collections::HashMap;
+use metrics::OutputMetrics;
+use config;
+use output::index_pattern::IndexPattern;
+use output::es_client::{Client, ItemResult, BulkError, BulkOptions, OpType, Doc, Auth, Tls};
+use md5;
+
+pub const DEFAULT_BUFFER_MAX : usize = 10000;
 
-static mut HANDLE: Option<Arc<JoinHandle<()>>> = None;
-static mut CHANNEL: Option<SyncSender<Arc<JValue>>> = None;
-static THREAD: Once = ONCE_INIT;
-
-/* we don't really want to create an index  - we'll just let it fail loudly
-static IDXQUERY : &'static str = r#"
-{
-  "mappings": {
-    "elasticsearch": {
-      "properties": {
-        "event_id": {
-          "type":  "string",
-          "index": "not_analyzed" 
-        }
-      }
+#[derive(RustcDecodable, Debug, Clone, PartialEq)]
+pub struct Config {
+    pub index : Option<String>,
+    pub doc_type : Option<String>,
+    pub host : Option<String>,
+    pub hosts : Option<Vec<String>>,
+    pub host_env_var : Option<String>,
+    pub port : Option<u16>,
+    pub batch_max_size : Option<usize>,
+    pub batch_secs : Option<u64>,
+    pub request_timeout_secs : Option<u64>,
+    pub retry_max_secs : Option<u64>,
+    pub dead_letter_path : Option<String>,
+    pub scheme : Option<String>,
+    pub username : Option<String>,
+    pub password : Option<String>,
+    pub password_env : Option<String>,
+    pub password_file : Option<String>,
+    pub api_key : Option<String>,
+    pub api_key_env : Option<String>,
+    pub api_key_file : Option<String>,
+    pub ca_file : Option<String>,
+    pub client_cert_file : Option<String>,
+    pub client_key_file : Option<String>,
+    pub node_cooldown_secs : Option<u64>,
+    pub id_field : Option<String>,
+    pub id_hash : Option<bool>,
+    pub op_type : Option<String>,
+    pub pipeline : Option<String>
+}
+
+impl Config {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Err(e) = self.urls() {
+            errors.push(e);
+        }
+        if let Err(e) = IndexPattern::parse(self.index.as_ref().map_or("logs", |i| i.as_str())) {
+            errors.push(e);
+        }
+        if let Some(ref p) = self.dead_letter_path {
+            match Path::new(p).parent() {
+                Some(dir) if dir == Path::new("") || dir.is_dir() => {},
+                _ => errors.push(format!("dead_letter_path {} is not in a directory which exists", p))
+            }
+        }
+        if self.id_field.is_some() && self.id_hash.unwrap_or(false) {
+            errors.push("only one of id_field and id_hash may be given".to_owned());
+        }
+        if let Some(ref f) = self.id_field {
+            if !f.starts_with('/') {
+                errors.push(format!("id_field {} should be a JSON pointer such as /{}", f, f));
+            }
+        }
+        if let Err(e) = OpType::parse(self.op_type.as_ref().map_or("index", |o| o.as_str())) {
+            errors.push(e);
+        }
+        if let Err(e) = self.auth() {
+            errors.push(e);
+        }
+        match self.tls() {
+            Ok(Some(tls)) => if let Err(e) = tls.connector() {
+                errors.push(e);
+            },
+            Ok(None) => {},
+            Err(e) => errors.push(e)
+        }
+        errors
     }
-  }
-}'
-"#;
-*/
-
-pub fn spawn(cfg: Table) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
-    THREAD.call_once(|| {
-        let bufmax = 
-            if let Some(bm) = cfg.get("buffer_max") {
-                bm.as_integer().unwrap() as usize
-            } else {
-                10000
-            };
-
-        let (tx, rx) = sync_channel(bufmax);
-        let handle = thread::spawn(|| {
-            run(cfg, rx);
-        });
-        unsafe {
-            CHANNEL = Some(tx);
-            HANDLE = Some(Arc::new(handle));
-        }
-    });
-    unsafe {
-        (HANDLE.as_ref().unwrap().clone(), CHANNEL.as_ref().unwrap().clone())  
+
+    /// The url of every node.  Hosts without a port are given `port`.
+    fn urls(&self) -> Result<Vec<String>, String> {
+        let hosts = match (&self.host, &self.hosts, &self.host_env_var) {
+            (&None, &None, &None) => vec!["localhost".to_owned()],
+            (&Some(ref h), &None, &None) => vec![h.clone()],
+            (&None, &Some(ref hosts), &None) => hosts.clone(),
+            // a comma separated list, as a service discovery tool might give
+            (&None, &None, &Some(ref key)) => match env::var(key) {
+                Ok(hosts) => hosts.split(',').map(|h| h.trim().to_owned()).filter(|h| !h.is_empty()).collect(),
+                Err(e) => return Err(format!("couldn't find {} in env: {}", key, e))
+            },
+            _ => return Err("only one of host, hosts and host_env_var may be given".to_owned())
+        };
+        if hosts.is_empty() {
+            return Err("no ES hosts were given".to_owned());
+        }
+        let scheme = self.scheme.as_ref().map_or("http", |s| s.as_str());
+        let port = self.port.unwrap_or(9200);
+        Ok(hosts.iter().map(|h| if h.contains(':') {
+            format!("{}://{}", scheme, h)
+        } else {
+            format!("{}://{}:{}", scheme, h, port)
+        }).collect())
+    }
+
+    /// The credentials to send, from the config, the environment or files
+    fn auth(&self) -> Result<Option<Auth>, String> {
+        let password = config::secret("password", &self.password, &self.password_env, &self.password_file)?;
+        let api_key = config::secret("api_key", &self.api_key, &self.api_key_env, &self.api_key_file)?;
+        match (&self.username, password, api_key) {
+            (&None, None, None) => Ok(None),
+            (&Some(ref username), Some(password), None) => Ok(Some(Auth::Basic { username : username.clone(), password : password })),
+            (&None, None, Some(key)) => Ok(Some(Auth::ApiKey(key))),
+            (_, _, Some(_)) => Err("an api_key can't be given with a username and password".to_owned()),
+            _ => Err("a username and password must be given together".to_owned())
+        }
+    }
+
+    /// The files to connect with when `scheme` is https
+    fn tls(&self) -> Result<Option<Tls>, String> {
+        let tls = Tls { ca_file : self.ca_file.clone(), cert_file : self.client_cert_file.clone(), key_file : self.client_key_file.clone() };
+        match self.scheme.as_ref().map_or("http", |s| s.as_str()) {
+            "http" if tls.ca_file.is_some() || tls.cert_file.is_some() || tls.key_file.is_some() =>
+                Err("ca_file, client_cert_file and client_key_file need scheme = \"https\"".to_owned()),
+            "http" => Ok(None),
+            "https" if tls.cert_file.is_some() != tls.key_file.is_some() =>
+                Err("client_cert_file and client_key_file must be given together".to_owned()),
+            "https" => Ok(Some(tls)),
+            s => Err(format!("scheme {} should be http or https", s))
+        }
     }
 }
 
+pub fn spawn(name: String, cfg: Config, bufmax: usize) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
+    let (tx, rx) = sync_channel(bufmax);
+    let handle = thread::Builder::new().name(name.clone()).spawn(move || {
+        run(name, cfg, rx);
+    }).unwrap();
+    (Arc::new(handle), tx)
+}
 
-fn run(cfg : Table, rx : Receiver<Arc<JValue>>) {
 
-    let default_index = Value::String("logs".to_string());
-    let default_doc_type = Value::String("default".to_string());
-    let default_host = Value::String("localhost".to_string());
-    let index =      cfg.get("index").unwrap_or(&default_index).as_str().unwrap_or("logs");
-    let doctype =    cfg.get("type").unwrap_or(&default_doc_type).as_str().unwrap_or("default");
-    let cfghost =       cfg.get("host").unwrap_or(&default_host).as_str().unwrap_or("localhost");
-    let port =       cfg.get("port").unwrap_or(&Value::Integer(9200)).as_integer().unwrap_or(9200);
-    let batch_max =  cfg.get("batch_max_size").unwrap_or(&Value::Integer(1_000)).as_integer().unwrap_or(1_000);
-    let batch_secs = cfg.get("batch_secs").unwrap_or(&Value::Integer(10)).as_integer().unwrap_or(10) as u64;
-    let batch_dur = Duration::from_secs(batch_secs);
-    let host_env_var = cfg.get("host_env_var").map(|h| h.as_str().unwrap());
+/// An ES node, which is left alone for a while after a request to it fails
+struct Node {
+    url : String,
+    client : Client,
+    dead_until : Option<Instant>
+}
 
-    let host = if let Some(key) = host_env_var {
-        match env::var(key) {
-            Ok(ip) => ip,
-            Err(e) => panic!("couldn't find {} in env: {}", key, e),
+/// Spreads bulk requests across the nodes in turn, skipping those which are cooling down
+struct Nodes {
+    nodes : Vec<Node>,
+    next : usize,
+    cooldown : Duration
+}
+
+impl Nodes {
+    /// The next node which is not cooling down, if there is one
+    fn pick(&mut self) -> Option<usize> {
+        let now = Instant::now();
+        for i in 0..self.nodes.len() {
+            let n = (self.next + i) % self.nodes.len();
+            if self.nodes[n].dead_until.map_or(true, |t| now >= t) {
+                self.next = n + 1;
+                return Some(n);
+            }
         }
-    } else {
-        cfghost.to_owned()
+        None
+    }
+
+    /// Sends a bulk request, trying each node which is alive until one of them answers
+    fn bulk(&mut self, index : &str, opts : &BulkOptions, docs : &[Doc]) -> Result<Vec<ItemResult>, BulkError> {
+        let mut failure = BulkError::Transient("every ES node is cooling down after failing".to_owned());
+        while let Some(n) = self.pick() {
+            let node = &mut self.nodes[n];
+            match node.client.bulk(index, opts, docs) {
+                Err(e) => if e.is_transient() {
+                    error!("ES node {} failed, leaving it for {:?} : {}", node.url, self.cooldown, e);
+                    node.dead_until = Some(Instant::now() + self.cooldown);
+                    failure = e;
+                } else {
+                    return Err(e);
+                },
+                result => {
+                    if node.dead_until.take().is_some() {
+                        println!("ES node {} is back", node.url);
+                    }
+                    return result;
+                }
+            }
+        }
+        Err(failure)
+    }
+}
+
+/// The id a message is indexed with, so that sending it again overwrites it rather than adding
+/// a duplicate.  It is taken from `id_field` or is a hash of the whole message, and without
+/// either ES makes one up.
+fn doc_id(cfg : &Config, msg : &JValue) -> Option<String> {
+    match cfg.id_field {
+        Some(ref ptr) => match msg.pointer(ptr) {
+            Some(&JValue::String(ref s)) => Some(s.clone()),
+            Some(&JValue::Null) | None => None,
+            Some(v) => Some(v.to_string())
+        },
+        None if cfg.id_hash.unwrap_or(false) => {
+            let digest = md5::compute(msg.to_string().as_bytes());
+            Some(digest.iter().map(|b| format!("{:02x}", b)).collect())
+        },
+        None => None
+    }
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    output : &'a str,
+    index : &'a str,
+    error : &'a str,
+    document : &'a JValue
+}
+
+/// Keeps a document which ES would not index, with the reason, so it can be fixed and sent again
+fn dead_letter(name : &str, file : &mut Option<File>, index : &str, doc : &JValue, error : &str) {
+    match *file {
+        Some(ref mut f) => {
+            let letter = DeadLetter { output : name, index : index, error : error, document : doc };
+            let mut line = serde_json::to_vec(&letter).unwrap_or(Vec::new());
+            line.push(b'\n');
+            if let Err(e) = f.write_all(&line) {
+                error!("{} could not write a dead letter for {} : {}", name, index, e);
+            }
+        },
+        None => error!("{} dropping a document for {} which ES would not index : {}", name, index, error)
+    }
+}
+
+fn run(name : String, cfg : Config, rx : Receiver<Arc<JValue>>) {
+
+    let index =      IndexPattern::parse(cfg.index.as_ref().map_or("logs", |i| i.as_str())).unwrap();
+    let opts = BulkOptions {
+        doc_type : cfg.doc_type.clone(),
+        op_type : OpType::parse(cfg.op_type.as_ref().map_or("index", |o| o.as_str())).unwrap(),
+        pipeline : cfg.pipeline.clone()
     };
+    let batch_max =  cfg.batch_max_size.unwrap_or(1_000);
+    let batch_secs = cfg.batch_secs.unwrap_or(10);
+    let batch_dur = Duration::from_secs(batch_secs);
+    let timeout =    Duration::from_secs(cfg.request_timeout_secs.unwrap_or(60));
+    let retry_max =  Duration::from_secs(cfg.retry_max_secs.unwrap_or(300));
+    let cooldown =   Duration::from_secs(cfg.node_cooldown_secs.unwrap_or(10));
 
-    let url = format!("http://{}:{}", host, port);
+    let metrics = OutputMetrics::new(&name);
+    let mut dead_letters = cfg.dead_letter_path.as_ref().map(|p| OpenOptions::new().append(true).create(true).open(p).unwrap());
 
+    let auth = cfg.auth().unwrap();
+    let tls = cfg.tls().unwrap();
+    let urls = cfg.urls().unwrap();
+    println!("{} sending to ES at {}", name, urls.join(", "));
+    let mut nodes = Nodes {
+        nodes : urls.into_iter().map(|url| {
+            let client = Client::new(&url, timeout, auth.clone(), tls.as_ref()).unwrap();
+            Node { url : url, client : client, dead_until : None }
+        }).collect(),
+        next : 0,
+        cooldown : cooldown
+    };
+
+    let to = Duration::from_millis(100);
+    let mut last = Instant::now();
+    let mut count = 0;
+    // documents waiting to be indexed, by the index they resolved to.  Those which ES was too
+    // busy to take, or which were sent while it was unreachable, stay here until they are indexed.
+    let mut batches = HashMap::<String, Vec<Doc>>::new();
+    let mut backoff = Duration::from_secs(1);
+    let mut retry_at = Instant::now();
     let mut running = true;
-    let mut failcount = 0;
-    let mut connected = false;
-
-    /*  Don't create a new index
-    let client = hyperclient::Client::new();
-    let idxurl = format!("{}/{}", url, index);
-    if let Err(e) = client.put(&idxurl).body(IDXQUERY).send() {
-        error!("Failed to create index : {}", e);
-    }
-    */
-
-    while running && failcount < 20 {
-        println!("Connecting to ES at {}", url);
-        let mut client = Client::new(&url).unwrap();
-
-        match client.open_index(index) {
-            Err(EsError::EsError(err)) => { failcount += 1; error!("Error opening index: {}", err)},
-            Err(EsError::EsServerError(err)) => { failcount += 1; error!("Error opening index: {}", err)},
-            Err(EsError::HttpError(err)) => { failcount += 1; error!("Error connecting to ES: {}", err)},
-            Err(EsError::IoError(err)) => { failcount += 1; error!("Error connecting to ES: {}", err)},
-            Err(EsError::JsonError(err)) => error!("Error sending to ES, malformed JSON: {}", err),
-            _ => { connected = true}
-        }
-        let mut last = Instant::now();
-        let to = Duration::from_millis(100);
-        let mut count = 0;
-        let mut batch = Vec::<Action<JValue>>::with_capacity(batch_max as usize);
-        while connected { 
+
+    // ES being unreachable is never fatal; documents wait for it, and once a batch is full
+    // the channel fills and the routes drop what doesn't fit
+    loop {
+        // while a full batch waits to be retried no more messages are taken, so that they back
+        // up in the channel rather than in memory
+        if count < batch_max {
             match rx.recv_timeout(to) {
-                Ok(msg) => {  batch.push(Action::index((*msg).clone()).with_doc_type(doctype));
-                              count += 1; 
+                Ok(msg) => {  metrics.depth.dec();
+                              let doc = Doc { id : doc_id(&cfg, &msg), source : msg.clone() };
+                              batches.entry(index.resolve(&msg)).or_insert_with(Vec::new).push(doc);
+                              count += 1;
                 },
                 Err(RecvTimeoutError::Disconnected) => { running = false; error!("Main loop channel disconnected. Shutting down."); }
                 Err(RecvTimeoutError::Timeout) => {},
             }
-            if last.elapsed() > batch_dur || count > batch_max {
-                // deploy zie batch!
-                //
-                if !batch.is_empty() {
-                    let op_start = Instant::now();
-                    match client.bulk(&batch).with_index(index).send() {
-                        Err(EsError::EsError(err)) => error!("Error in bulk indexing operation: {}", err),
-                        Err(EsError::EsServerError(err)) => error!("Error in bulk indexing operation: {}", err),
-                        Err(EsError::HttpError(err)) => { connected = false; failcount += 1; error!("Error sending data to ES: {}", err)},
-                        Err(EsError::IoError(err)) => { connected = false; failcount += 1; error!("Error sending data to ES: {}", err)},
-                        Err(EsError::JsonError(err)) => error!("Error sending to ES, malformed JSON: {}", err),
-                        _ => {}
+        } else {
+            thread::sleep(to);
+        }
+
+        // on shutdown the final batch goes out immediately
+        let due = !running || last.elapsed() > batch_dur || count >= batch_max;
+        if due && count > 0 && (!running || Instant::now() >= retry_at) {
+            let op_start = Instant::now();
+            // a bulk request for each index the batch's documents resolved to
+            for (idx, docs) in mem::replace(&mut batches, HashMap::new()) {
+                match nodes.bulk(&idx, &opts, &docs) {
+                    Ok(results) => {
+                        let sent = docs.len();
+                        let mut retry = Vec::new();
+                        for (doc, result) in docs.into_iter().zip(results.into_iter()) {
+                            match result {
+                                ItemResult::Indexed => {},
+                                ItemResult::Retry(_) => retry.push(doc),
+                                ItemResult::Failed(status, err) => {
+                                    metrics.errors.inc();
+                                    dead_letter(&name, &mut dead_letters, &idx, &doc.source, &format!("{} {}", status, err));
+                                }
+                            }
+                        }
+                        metrics.batch_size.observe((sent - retry.len()) as f64);
+                        if !retry.is_empty() {
+                            metrics.errors.add(retry.len());
+                            error!("ES was too busy or failing to index {} documents for {}", retry.len(), idx);
+                            batches.insert(idx, retry);
+                        }
+                    },
+                    Err(ref e) if e.is_transient() => {
+                        metrics.errors.inc();
+                        error!("Error sending data to ES: {}", e);
+                        batches.insert(idx, docs);
+                    },
+                    Err(e) => {
+                        metrics.errors.inc();
+                        error!("ES refused a bulk request of {} documents for {} : {}", docs.len(), idx, e);
+                        for doc in docs.iter() {
+                            dead_letter(&name, &mut dead_letters, &idx, &doc.source, &e.to_string());
+                        }
                     }
-                    let op_duration = op_start.elapsed();
-                    info!("Batch operation took {:?}", op_duration);
-                    if op_duration > batch_dur {
-                        error!("Batch operation took {:?} which is longer than the batch delay {:?}", op_duration, batch_dur);
+                }
+            }
+            count = batches.values().map(|d| d.len()).sum();
+            if count > 0 {
+                retry_at = Instant::now() + backoff;
+                error!("{} has {} documents to send again, retrying in {:?}", name, count, backoff);
+                backoff = cmp::min(backoff * 2, retry_max);
+            } else {
+                backoff = Duration::from_secs(1);
+            }
+
+            let op_duration = op_start.elapsed();
+            metrics.batch_seconds.observe_duration(op_duration);
+            info!("Batch operation took {:?}", op_duration);
+            if op_duration > batch_dur {
+                error!("Batch operation took {:?} which is longer than the batch delay {:?}", op_duration, batch_dur);
+            }
+            last = Instant::now();
+        }
+
+        if !running {
+            if count > 0 {
+                error!("{} is shutting down with {} documents it could not index", name, count);
+                for (idx, docs) in batches.drain() {
+                    for doc in docs.iter() {
+                        dead_letter(&name, &mut dead_letters, &idx, &doc.source, "not indexed before lout shut down");
                     }
-                    batch.clear();
-                    count = 0;
                 }
-                last = Instant::now();
             }
+            break;
         }
     }
 
-    if failcount >= 20 {
-        error!("Failed 20 times attempting to connect. Giving up");
-    } else {
-        error!("ES output shutting down gracefully");
-    }
+    error!("ES output {} shutting down gracefully", name);
 }
-
-
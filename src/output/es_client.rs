@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+use hyper::{self, Client as HttpClient, Url};
+use hyper::header::{ContentType, Authorization, Basic};
+use hyper::net::HttpsConnector;
+use hyper_openssl::OpensslClient;
+use openssl::ssl::{SslConnectorBuilder, SslMethod};
+use openssl::x509::X509_FILETYPE_PEM;
+use hyper::status::StatusCode;
+use serde_json::{self, Value as JValue};
+
+#[derive(Serialize)]
+struct ActionMeta<'a> {
+    #[serde(rename = "_type", skip_serializing_if = "Option::is_none")]
+    doc_type : Option<&'a str>,
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    id : Option<&'a str>
+}
+
+/// Whether a document replaces any with the same id, or is only written if there is none
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpType {
+    Index,
+    Create
+}
+
+impl OpType {
+    pub fn parse(op : &str) -> Result<OpType, String> {
+        match op {
+            "index" => Ok(OpType::Index),
+            "create" => Ok(OpType::Create),
+            op => Err(format!("op_type {} should be index or create", op))
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            OpType::Index => "index",
+            OpType::Create => "create"
+        }
+    }
+}
+
+/// How the documents of a bulk request are written
+#[derive(Debug, Clone, PartialEq)]
+pub struct BulkOptions {
+    /// The `_type` of each document, which ES before 7 needs and ES 8 refuses
+    pub doc_type : Option<String>,
+    pub op_type : OpType,
+    /// An ingest pipeline to put the documents through
+    pub pipeline : Option<String>
+}
+
+/// A document, with the id it is written with; without one ES makes one up
+#[derive(Debug, Clone, PartialEq)]
+pub struct Doc {
+    pub id : Option<String>,
+    pub source : Arc<JValue>
+}
+
+#[derive(Debug)]
+pub enum BulkError {
+    /// ES could not be reached or was not able to serve the request; it is worth trying again
+    Transient(String),
+    /// ES refused the whole request, and would refuse it again
+    Permanent(String)
+}
+
+impl BulkError {
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            BulkError::Transient(_) => true,
+            BulkError::Permanent(_) => false
+        }
+    }
+}
+
+impl Display for BulkError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            BulkError::Transient(ref e) => write!(f, "{} (will retry)", e),
+            BulkError::Permanent(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+/// What became of one document in a bulk request
+#[derive(Debug, Clone, PartialEq)]
+pub enum ItemResult {
+    Indexed,
+    /// ES was too busy or failed to take the document, so it is worth sending again
+    Retry(String),
+    /// ES refused the document with this status, as it would again
+    Failed(u64, String)
+}
+
+/// How requests are authenticated
+#[derive(Debug, Clone, PartialEq)]
+pub enum Auth {
+    Basic { username : String, password : String },
+    /// The base64 encoded `id:api_key` which ES gives when a key is created
+    ApiKey(String)
+}
+
+/// The files for an https connection.  Without a `ca_file` the server's certificate is checked
+/// against the system's CAs, or the bundle named by `SSL_CERT_FILE`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tls {
+    pub ca_file : Option<String>,
+    pub cert_file : Option<String>,
+    pub key_file : Option<String>
+}
+
+impl Tls {
+    pub fn connector(&self) -> Result<HttpsConnector<OpensslClient>, String> {
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls()).map_err(|e| e.to_string())?;
+        {
+            let ctx = builder.builder_mut();
+            if let Some(ref ca) = self.ca_file {
+                ctx.set_ca_file(ca).map_err(|e| format!("ca_file {} : {}", ca, e))?;
+            }
+            if let (&Some(ref cert), &Some(ref key)) = (&self.cert_file, &self.key_file) {
+                ctx.set_certificate_chain_file(cert).map_err(|e| format!("client_cert_file {} : {}", cert, e))?;
+                ctx.set_private_key_file(key, X509_FILETYPE_PEM).map_err(|e| format!("client_key_file {} : {}", key, e))?;
+                ctx.check_private_key().map_err(|e| format!("client_key_file {} does not match client_cert_file {} : {}", key, cert, e))?;
+            }
+        }
+        Ok(HttpsConnector::new(OpensslClient::from(builder.build())))
+    }
+}
+
+/// A client for the bulk API of an ES node
+pub struct Client {
+    url : String,
+    http : HttpClient,
+    auth : Option<Auth>
+}
+
+impl Client {
+    /// A client for `url`, which connects with `tls` if it is given
+    pub fn new(url : &str, timeout : Duration, auth : Option<Auth>, tls : Option<&Tls>) -> Result<Client, String> {
+        let mut http = match tls {
+            Some(tls) => HttpClient::with_connector(tls.connector()?),
+            None => HttpClient::new()
+        };
+        http.set_read_timeout(Some(timeout));
+        http.set_write_timeout(Some(timeout));
+        Ok(Client { url : url.trim_right_matches('/').to_owned(), http : http, auth : auth })
+    }
+
+    /// Writes `docs` to `index`, giving the result for each document in the same order
+    pub fn bulk(&self, index : &str, opts : &BulkOptions, docs : &[Doc]) -> Result<Vec<ItemResult>, BulkError> {
+        let mut body = Vec::new();
+        for doc in docs.iter() {
+            let mut action = BTreeMap::new();
+            action.insert(opts.op_type.name(), ActionMeta { doc_type : opts.doc_type.as_ref().map(|t| t.as_str()), id : doc.id.as_ref().map(|i| i.as_str()) });
+            serde_json::to_writer(&mut body, &action).map_err(|e| BulkError::Permanent(e.to_string()))?;
+            body.push(b'\n');
+            serde_json::to_writer(&mut body, &*doc.source).map_err(|e| BulkError::Permanent(e.to_string()))?;
+            body.push(b'\n');
+        }
+
+        let url = format!("{}/{}/_bulk", self.url, index);
+        let url = match opts.pipeline {
+            // the pipeline's name is escaped, as it may hold `&`, `#` or spaces
+            Some(ref p) => Url::parse_with_params(&url, &[("pipeline", p)])
+                .map_err(|e| BulkError::Permanent(format!("{} is not a valid url : {}", url, e)))?.into_string(),
+            None => url
+        };
+        let req = self.http.post(&url).header(ContentType::json()).body(&body[..]);
+        let req = match self.auth {
+            Some(Auth::Basic { ref username, ref password }) =>
+                req.header(Authorization(Basic { username : username.clone(), password : Some(password.clone()) })),
+            Some(Auth::ApiKey(ref key)) => req.header(Authorization(format!("ApiKey {}", key))),
+            None => req
+        };
+        let mut res = match req.send() {
+            Ok(res) => res,
+            Err(hyper::Error::Uri(e)) => return Err(BulkError::Permanent(format!("{} is not a valid url : {}", url, e))),
+            Err(e) => return Err(BulkError::Transient(format!("bulk request to {} failed : {}", url, e)))
+        };
+        let mut resbody = String::new();
+        if let Err(e) = res.read_to_string(&mut resbody) {
+            return Err(BulkError::Transient(format!("bulk request to {} failed reading the response : {}", url, e)));
+        }
+        match res.status {
+            s if s.is_success() => {},
+            // credentials may be part way through being rotated
+            s @ StatusCode::TooManyRequests | s @ StatusCode::RequestTimeout | s @ StatusCode::Unauthorized | s @ StatusCode::Forbidden =>
+                return Err(BulkError::Transient(format!("bulk request to {} returned {} : {}", url, s, resbody))),
+            s if s.is_server_error() => return Err(BulkError::Transient(format!("bulk request to {} returned {} : {}", url, s, resbody))),
+            s => return Err(BulkError::Permanent(format!("bulk request to {} returned {} : {}", url, s, resbody)))
+        }
+
+        let res : JValue = serde_json::from_str(&resbody)
+            .map_err(|e| BulkError::Permanent(format!("bulk request to {} returned an unexpected response {} : {}", url, resbody, e)))?;
+        let items = match res.get("items").and_then(|i| i.as_array()) {
+            Some(items) if items.len() == docs.len() => items,
+            _ => return Err(BulkError::Permanent(format!("bulk request to {} returned an unexpected response {}", url, resbody)))
+        };
+        let mut results = Vec::with_capacity(items.len());
+        for item in items.iter() {
+            // each item is keyed by its action
+            let result = item.as_object().and_then(|o| o.values().next());
+            let status = result.and_then(|r| r.get("status")).and_then(|s| s.as_u64()).unwrap_or(0);
+            let error = result.and_then(|r| r.get("error")).map_or(String::new(), |e| e.to_string());
+            results.push(match status {
+                200...299 => ItemResult::Indexed,
+                // the document was written by an earlier attempt
+                409 if opts.op_type == OpType::Create => ItemResult::Indexed,
+                // not the document's fault, so the whole request is tried again
+                401 | 403 => return Err(BulkError::Transient(format!("bulk request to {} was not authorized : {}", url, error))),
+                429 | 500...599 => ItemResult::Retry(error),
+                s => ItemResult::Failed(s, error)
+            });
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Client, ItemResult, Auth, BulkOptions, OpType, Doc};
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use hyper::server::{Server, Request, Response};
+    use hyper::status::StatusCode;
+    use hyper::uri::RequestUri;
+    use serde_json::de;
+
+    /// Starts a fake ES node which records each request, answering with `status` and `body`
+    fn fake_es(status : StatusCode, body : &'static str) -> (String, Arc<Mutex<Vec<(String, Option<String>, String)>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let mut listening = Server::http("127.0.0.1:0").unwrap().handle(move |mut req : Request, mut res : Response| {
+            let path = match req.uri {
+                RequestUri::AbsolutePath(ref p) => p.clone(),
+                _ => String::new()
+            };
+            let mut reqbody = String::new();
+            req.read_to_string(&mut reqbody).unwrap();
+            let auth = req.headers.get_raw("Authorization").map(|v| String::from_utf8_lossy(&v[0]).into_owned());
+            recorded.lock().unwrap().push((path, auth, reqbody));
+            *res.status_mut() = status;
+            res.send(body.as_bytes()).unwrap();
+        }).unwrap();
+        let url = format!("http://{}", listening.socket);
+        // the server runs for the rest of the test
+        thread::spawn(move || drop(listening));
+        (url, requests)
+    }
+
+#[test]
+    fn es_bulk_items() {
+        let (url, requests) = fake_es(StatusCode::Ok, r#"{"took":3,"errors":true,"items":[
+            {"index":{"_index":"logs","status":201}},
+            {"index":{"_index":"logs","status":429,"error":{"type":"es_rejected_execution_exception"}}},
+            {"index":{"_index":"logs","status":400,"error":{"type":"mapper_parsing_exception"}}}]}"#);
+        let auth = Auth::Basic { username : "lout".to_owned(), password : "hunter2".to_owned() };
+        let client = Client::new(&url, Duration::from_secs(5), Some(auth), None).unwrap();
+        let docs = vec![Doc { id : None, source : Arc::new(de::from_str(r#"{"a":1}"#).unwrap()) },
+                        Doc { id : Some("b".to_owned()), source : Arc::new(de::from_str(r#"{"a":2}"#).unwrap()) },
+                        Doc { id : None, source : Arc::new(de::from_str(r#"{"a":"x"}"#).unwrap()) }];
+        let opts = BulkOptions { doc_type : Some("gelf".to_owned()), op_type : OpType::Index, pipeline : None };
+        let results = client.bulk("logs", &opts, &docs).unwrap();
+        assert_eq!(results[0], ItemResult::Indexed);
+        assert_eq!(results[1], ItemResult::Retry(r#"{"type":"es_rejected_execution_exception"}"#.to_owned()));
+        assert_eq!(results[2], ItemResult::Failed(400, r#"{"type":"mapper_parsing_exception"}"#.to_owned()));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].0, "/logs/_bulk");
+        assert_eq!(requests[0].1, Some("Basic bG91dDpodW50ZXIy".to_owned()));
+        let lines : Vec<&str> = requests[0].2.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], r#"{"index":{"_type":"gelf"}}"#);
+        assert_eq!(lines[2], r#"{"index":{"_type":"gelf","_id":"b"}}"#);
+        drop(requests);
+
+        // a conflict on create means an earlier attempt got the document in
+        let (url, requests) = fake_es(StatusCode::Ok, r#"{"took":3,"errors":true,"items":[
+            {"create":{"_index":"logs","status":201}},
+            {"create":{"_index":"logs","status":409,"error":{"type":"version_conflict_engine_exception"}}},
+            {"create":{"_index":"logs","status":201}}]}"#);
+        let create = BulkOptions { doc_type : None, op_type : OpType::Create, pipeline : Some("geo ip&v=2#1".to_owned()) };
+        let results = Client::new(&url, Duration::from_secs(5), None, None).unwrap().bulk("logs", &create, &docs).unwrap();
+        assert!(results.iter().all(|r| *r == ItemResult::Indexed));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].0, "/logs/_bulk?pipeline=geo+ip%26v%3D2%231");
+        // without a doc type none is sent
+        assert!(requests[0].2.starts_with(r#"{"create":{}}"#));
+        drop(requests);
+
+        let (url, requests) = fake_es(StatusCode::ServiceUnavailable, "busy");
+        let client = Client::new(&url, Duration::from_secs(5), Some(Auth::ApiKey("a2V5".to_owned())), None).unwrap();
+        assert!(client.bulk("logs", &opts, &docs).unwrap_err().is_transient());
+        assert_eq!(requests.lock().unwrap()[0].1, Some("ApiKey a2V5".to_owned()));
+        for status in vec![StatusCode::BadGateway, StatusCode::GatewayTimeout, StatusCode::Unauthorized, StatusCode::Forbidden] {
+            let (url, _) = fake_es(status, "later");
+            assert!(Client::new(&url, Duration::from_secs(5), None, None).unwrap().bulk("logs", &opts, &docs).unwrap_err().is_transient());
+        }
+        let (url, _) = fake_es(StatusCode::Ok, r#"{"took":3,"errors":true,"items":[
+            {"index":{"_index":"logs","status":201}},
+            {"index":{"_index":"logs","status":502,"error":{"type":"node_disconnected_exception"}}},
+            {"index":{"_index":"logs","status":403,"error":{"type":"security_exception"}}}]}"#);
+        assert!(Client::new(&url, Duration::from_secs(5), None, None).unwrap().bulk("logs", &opts, &docs).unwrap_err().is_transient());
+        let (url, _) = fake_es(StatusCode::Ok, r#"{"took":3,"errors":true,"items":[
+            {"index":{"_index":"logs","status":500,"error":{"type":"exception"}}},
+            {"index":{"_index":"logs","status":504,"error":{"type":"timeout"}}},
+            {"index":{"_index":"logs","status":201}}]}"#);
+        let results = Client::new(&url, Duration::from_secs(5), None, None).unwrap().bulk("logs", &opts, &docs).unwrap();
+        assert_eq!(results[0], ItemResult::Retry(r#"{"type":"exception"}"#.to_owned()));
+        assert_eq!(results[1], ItemResult::Retry(r#"{"type":"timeout"}"#.to_owned()));
+        let (url, _) = fake_es(StatusCode::BadRequest, "nope");
+        assert!(!Client::new(&url, Duration::from_secs(5), None, None).unwrap().bulk("logs", &opts, &docs).unwrap_err().is_transient());
+        assert!(Client::new("http://127.0.0.1:1", Duration::from_secs(5), None, None).unwrap().bulk("logs", &opts, &docs).unwrap_err().is_transient());
+    }
+}
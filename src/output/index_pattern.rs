@@ -0,0 +1,126 @@
+use serde_json::Value as JValue;
+use chrono::format::{StrftimeItems, Item};
+use output::key_template::message_time;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    // may hold strftime specifiers such as %Y
+    Time(String),
+    Field(String)
+}
+
+/// An index name such as `logs-{/service}-%Y.%m.%d`.  The strftime specifiers are filled from
+/// the message's GELF `timestamp`, or the time it arrived if it has none, and `{/field}` is any
+/// JSON pointer into the message.  Index names are lowercased, and can't start with `-`, `_` or `+`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexPattern {
+    parts : Vec<Part>
+}
+
+/// Keeps a message's field to characters which ES allows in an index name, replacing the dots
+/// of a value which is nothing but dots, as `.` and `..` aren't allowed
+fn sanitize(v : &str) -> String {
+    let only_dots = v.chars().all(|c| c == '.');
+    v.chars().flat_map(|c| c.to_lowercase())
+     .map(|c| if (c.is_alphanumeric() || "-_.".contains(c)) && !(only_dots && c == '.') { c } else { '_' }).collect()
+}
+
+impl IndexPattern {
+    pub fn parse(pattern : &str) -> Result<IndexPattern, String> {
+        let mut parts = Vec::new();
+        let mut rest = pattern;
+        loop {
+            let (time, field) = match rest.find('{') {
+                Some(open) => match rest[open..].find('}') {
+                    Some(c) => (&rest[..open], Some(&rest[open + 1..open + c])),
+                    None => return Err(format!("unclosed {{ in index {}", pattern))
+                },
+                None => (rest, None)
+            };
+            for item in StrftimeItems::new(time) {
+                match item {
+                    Item::Error => return Err(format!("index {} has an unknown % specifier", pattern)),
+                    Item::Literal(l) | Item::Space(l) => {
+                        if let Some(c) = l.chars().find(|c| c.is_uppercase() || "\\/*?\"<>| ,#:".contains(*c)) {
+                            return Err(format!("index {} can't contain '{}', ES index names are lowercase without \\/*?\"<>| ,#:", pattern, c));
+                        }
+                    },
+                    _ => {}
+                }
+            }
+            if parts.is_empty() && time.starts_with(|c| "-_+".contains(c)) {
+                return Err(format!("index {} can't start with -, _ or +", pattern));
+            }
+            if !time.is_empty() {
+                parts.push(Part::Time(time.to_owned()));
+            }
+            match field {
+                Some(f) if f.starts_with('/') => {
+                    parts.push(Part::Field(f.to_owned()));
+                    rest = &rest[time.len() + f.len() + 2..];
+                },
+                Some(f) => return Err(format!("{{{}}} in index {} should be a JSON pointer such as {{/service}}", f, pattern)),
+                None => break
+            }
+        }
+        Ok(IndexPattern { parts : parts })
+    }
+
+    /// The index `msg` belongs in
+    pub fn resolve(&self, msg : &JValue) -> String {
+        let time = message_time(msg);
+        let mut index = String::new();
+        for part in self.parts.iter() {
+            match *part {
+                // month and day names are capitalised
+                Part::Time(ref t) if t.contains('%') => index.push_str(&time.format(t).to_string().to_lowercase()),
+                Part::Time(ref t) => index.push_str(t),
+                Part::Field(ref ptr) => match msg.pointer(ptr) {
+                    Some(&JValue::String(ref s)) => index.push_str(&sanitize(s)),
+                    Some(&JValue::Null) | None => index.push_str("unknown"),
+                    Some(v) => index.push_str(&sanitize(&v.to_string()))
+                }
+            }
+        }
+        // only a field can start the name with these
+        let index = index.trim_left_matches(|c| "-_+".contains(c));
+        match index {
+            "" | "." | ".." => "unknown".to_owned(),
+            _ => index.to_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndexPattern;
+    use serde_json::de;
+
+#[test]
+    fn index_pattern_resolves() {
+        let p = IndexPattern::parse("logs-{/service}-%Y.%m.%d").unwrap();
+        let msg = de::from_str(r#"{"timestamp": 1489000000.25, "service": "Pay Ments"}"#).unwrap();
+        assert_eq!(p.resolve(&msg), "logs-pay_ments-2017.03.08");
+        let other = de::from_str(r#"{"timestamp": 1489000000}"#).unwrap();
+        assert_eq!(p.resolve(&other), "logs-unknown-2017.03.08");
+        assert_eq!(IndexPattern::parse("logs-%Y.%m").unwrap().resolve(&msg), "logs-2017.03");
+
+        let fixed = IndexPattern::parse("k8s-dev").unwrap();
+        assert_eq!(fixed.resolve(&msg), "k8s-dev");
+        assert_eq!(IndexPattern::parse("logs-%b").unwrap().resolve(&msg), "logs-mar");
+        let internal = de::from_str(r#"{"timestamp": 1489000000, "service": "_Internal"}"#).unwrap();
+        assert_eq!(IndexPattern::parse("{/service}-%Y").unwrap().resolve(&internal), "internal-2017");
+        let dots = de::from_str(r#"{"service": "..", "env": "."}"#).unwrap();
+        assert_eq!(IndexPattern::parse("{/service}").unwrap().resolve(&dots), "unknown");
+        assert_eq!(IndexPattern::parse("logs-{/service}{/env}").unwrap().resolve(&dots), "logs-___");
+        assert_eq!(IndexPattern::parse("{/env}.").unwrap().resolve(&dots), "unknown");
+
+        assert!(IndexPattern::parse("logs-{service}").is_err());
+        assert!(IndexPattern::parse("logs-{/service").is_err());
+        assert!(IndexPattern::parse("logs-%Q").is_err());
+        assert!(IndexPattern::parse("Logs-%Y").is_err());
+        assert!(IndexPattern::parse("logs %Y").is_err());
+        assert!(IndexPattern::parse("_logs").is_err());
+        assert!(IndexPattern::parse("logs-_{/service}").is_ok());
+    }
+}
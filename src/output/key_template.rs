@@ -0,0 +1,139 @@
+use serde_json::Value as JValue;
+use chrono::{DateTime, UTC, TimeZone, Datelike, Timelike};
+
+const TIMESTAMP : &'static str = "{timestamp}";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Year,
+    Month,
+    Day,
+    Hour,
+    Hostname,
+    Output,
+    Field(String),
+    // left in place until the batch is uploaded, so that every upload gets its own key
+    Timestamp
+}
+
+/// An object key such as `logs/{/service}/{year}/{month}/{day}/{hour}/{hostname}-{timestamp}.json`.
+/// The date and hour come from the message's GELF `timestamp`, or the time it arrived if it has none.
+/// `{/field}` is any JSON pointer into the message, and `{output}` is the name of the output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyTemplate {
+    parts : Vec<Part>
+}
+
+/// The time from a message's GELF `timestamp`, or now if it has none
+pub fn message_time(msg : &JValue) -> DateTime<UTC> {
+    msg.pointer("/timestamp").and_then(|t| t.as_f64())
+        .and_then(|t| UTC.timestamp_opt(t as i64, 0).single())
+        .unwrap_or_else(UTC::now)
+}
+
+/// Keeps a message's field to characters which are safe in one level of a key.  A value of only
+/// dots would be `.` or `..` when the key is used as a path, so its dots are replaced too.
+fn sanitize(v : &str) -> String {
+    if v.is_empty() {
+        return "unknown".to_owned();
+    }
+    let only_dots = v.chars().all(|c| c == '.');
+    v.chars().map(|c| if (c.is_alphanumeric() || "-_.=".contains(c)) && !(only_dots && c == '.') { c } else { '_' }).collect()
+}
+
+impl KeyTemplate {
+    pub fn parse(template : &str) -> Result<KeyTemplate, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_owned()));
+            }
+            let close = match rest[open..].find('}') {
+                Some(c) => open + c,
+                None => return Err(format!("unclosed {{ in key template {}", template))
+            };
+            let part = match &rest[open + 1..close] {
+                "year" => Part::Year,
+                "month" => Part::Month,
+                "day" => Part::Day,
+                "hour" => Part::Hour,
+                "hostname" => Part::Hostname,
+                "output" => Part::Output,
+                "timestamp" => Part::Timestamp,
+                p if p.starts_with('/') => Part::Field(p.to_owned()),
+                p => return Err(format!("unknown placeholder {{{}}} in key template {}", p, template))
+            };
+            parts.push(part);
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+        if !parts.contains(&Part::Timestamp) {
+            return Err(format!("key template {} must contain {{timestamp}}, or uploads would overwrite each other", template));
+        }
+        Ok(KeyTemplate { parts : parts })
+    }
+
+    /// Resolves everything but the upload time, giving the partition which `msg` belongs in
+    pub fn partition(&self, msg : &JValue, output : &str, hostname : &str) -> String {
+        let time = message_time(msg);
+        let mut key = String::new();
+        for part in self.parts.iter() {
+            match *part {
+                Part::Literal(ref l) => key.push_str(l),
+                Part::Year => key.push_str(&format!("{:04}", time.year())),
+                Part::Month => key.push_str(&format!("{:02}", time.month())),
+                Part::Day => key.push_str(&format!("{:02}", time.day())),
+                Part::Hour => key.push_str(&format!("{:02}", time.hour())),
+                Part::Hostname => key.push_str(hostname),
+                Part::Output => key.push_str(output),
+                Part::Field(ref ptr) => match msg.pointer(ptr) {
+                    Some(&JValue::String(ref s)) => key.push_str(&sanitize(s)),
+                    Some(&JValue::Null) | None => key.push_str("unknown"),
+                    Some(v) => key.push_str(&sanitize(&v.to_string()))
+                },
+                Part::Timestamp => key.push_str(TIMESTAMP)
+            }
+        }
+        key
+    }
+
+    /// The key for a batch from `partition` uploaded at `now`
+    pub fn key(partition : &str, now : DateTime<UTC>) -> String {
+        partition.replace(TIMESTAMP, &now.to_rfc3339().replace(":", "-").replace("+", "-"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyTemplate;
+    use serde_json::de;
+    use chrono::{UTC, TimeZone};
+
+#[test]
+    fn key_template_partitions() {
+        let t = KeyTemplate::parse("logs/service={/service}/{year}/{month}/{day}/{hour}/{output}-{hostname}-{timestamp}.json").unwrap();
+        let msg = de::from_str(r#"{"timestamp": 1489000000.25, "service": "pay/ments"}"#).unwrap();
+        let p = t.partition(&msg, "s3", "host1");
+        assert_eq!(p, "logs/service=pay_ments/2017/03/08/19/s3-host1-{timestamp}.json");
+        let other = de::from_str(r#"{"timestamp": 1489000000, "level": 3}"#).unwrap();
+        assert_eq!(t.partition(&other, "s3", "host1"), "logs/service=unknown/2017/03/08/19/s3-host1-{timestamp}.json");
+        let key = KeyTemplate::key(&p, UTC.timestamp(1489000100, 0));
+        assert_eq!(key, "logs/service=pay_ments/2017/03/08/19/s3-host1-2017-03-08T19-08-20-00-00.json");
+
+        let t = KeyTemplate::parse("{/service}/{timestamp}").unwrap();
+        let up = de::from_str(r#"{"timestamp": 1489000000, "service": ".."}"#).unwrap();
+        assert_eq!(t.partition(&up, "s3", "host1"), "__/{timestamp}");
+        let dotted = de::from_str(r#"{"timestamp": 1489000000, "service": "../v1.2"}"#).unwrap();
+        assert_eq!(t.partition(&dotted, "s3", "host1"), ".._v1.2/{timestamp}");
+        let empty = de::from_str(r#"{"timestamp": 1489000000, "service": ""}"#).unwrap();
+        assert_eq!(t.partition(&empty, "s3", "host1"), "unknown/{timestamp}");
+
+        assert!(KeyTemplate::parse("{year}/{hostname}").is_err());
+        assert!(KeyTemplate::parse("{minute}/{timestamp}").is_err());
+        assert!(KeyTemplate::parse("{/service/{timestamp}").is_err());
+    }
+}
@@ -1,6 +1,128 @@
-
 pub mod stdout;
 pub mod es;
 pub mod s3;
 pub mod postgres;
+pub mod pachyderm;
+pub mod parquet;
+mod disk_buffer;
+mod compression;
+mod key_template;
+mod index_pattern;
+mod es_client;
+mod parquet_file;
+mod pfs;
 mod translator;
+
+use toml::{Table, Value};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use serde_json::Value as JValue;
+use config::{self, OutputConfig};
+
+/// A running output thread and the channel which feeds it
+pub type Worker = (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>);
+
+/// The type specific options of an output
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    S3(s3::Config),
+    Es(es::Config),
+    Stdout(stdout::Config),
+    Postgres(postgres::Config),
+    Pachyderm(pachyderm::Config),
+    Parquet(parquet::Config)
+}
+
+impl Kind {
+    /// Decodes the options for an output of type `ty`, handing back any keys it does not use
+    pub fn decode(ty : &str, cfg : Value) -> Result<(Kind, Table), String> {
+        match ty {
+            "s3" => config::decode(cfg).map(|(c, rest)| (Kind::S3(c), rest)),
+            "es" | "elasticsearch" => config::decode(cfg).map(|(c, rest)| (Kind::Es(c), rest)),
+            "stdout" => config::decode(cfg).map(|(c, rest)| (Kind::Stdout(c), rest)),
+            "postgres" => config::decode(cfg).map(|(c, rest)| (Kind::Postgres(c), rest)),
+            "pachyderm" => config::decode(cfg).map(|(c, rest)| (Kind::Pachyderm(c), rest)),
+            "parquet" => config::decode(cfg).map(|(c, rest)| (Kind::Parquet(c), rest)),
+            t => Err(format!("{} is not a valid output type", t))
+        }
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        match *self {
+            Kind::S3(ref c) => c.validate(),
+            Kind::Es(ref c) => c.validate(),
+            Kind::Stdout(_) => Vec::new(),
+            Kind::Postgres(ref c) => c.validate(),
+            Kind::Pachyderm(ref c) => c.validate(),
+            Kind::Parquet(ref c) => c.validate()
+        }
+    }
+
+    fn default_buffer_max(&self) -> usize {
+        match *self {
+            Kind::S3(_) => s3::DEFAULT_BUFFER_MAX,
+            Kind::Es(_) => es::DEFAULT_BUFFER_MAX,
+            Kind::Stdout(_) => stdout::DEFAULT_BUFFER_MAX,
+            Kind::Postgres(_) => postgres::DEFAULT_BUFFER_MAX,
+            Kind::Pachyderm(_) => pachyderm::DEFAULT_BUFFER_MAX,
+            Kind::Parquet(_) => parquet::DEFAULT_BUFFER_MAX
+        }
+    }
+}
+
+/// Starts a new worker for the `[output.<name>]` table, each with its own thread and channel.
+/// With `buffer = "disk"` the worker is fronted by an on-disk queue.
+pub fn spawn(name : &str, cfg : &OutputConfig) -> Worker {
+    match cfg.buffer.buffer.as_ref().map(|b| b.as_str()) {
+        Some("disk") => {
+            let worker = spawn_worker(name, &cfg.kind, disk_buffer::WORKER_CHANNEL_SIZE);
+            let bufmax = cfg.buffer.buffer_max.unwrap_or(disk_buffer::DEFAULT_BUFFER_MAX);
+            disk_buffer::spawn(name.to_string(), &cfg.buffer, bufmax, worker)
+        },
+        _ => spawn_worker(name, &cfg.kind, cfg.buffer.buffer_max.unwrap_or(cfg.kind.default_buffer_max()))
+    }
+}
+
+/// Starts a replacement for the worker `previous`, which must already have been cut off from
+/// the routes.  Messages sent to the replacement wait in its channel until `previous` has sent
+/// its final batch and exited, so the two never share a batch file or disk buffer.
+pub fn spawn_after(name : &str, cfg : &OutputConfig, previous : Arc<JoinHandle<()>>) -> Worker {
+    let bufmax = cfg.buffer.buffer_max.unwrap_or(cfg.kind.default_buffer_max());
+    let (tx, rx) = sync_channel(bufmax);
+    let name = name.to_string();
+    let cfg = cfg.clone();
+    let handle = thread::Builder::new().name(format!("{}-restart", name)).spawn(move || {
+        let mut previous = previous;
+        loop {
+            match Arc::try_unwrap(previous) {
+                Ok(h) => { let _ = h.join(); break; },
+                Err(p) => { previous = p; thread::sleep(Duration::from_millis(100)); }
+            }
+        }
+        println!("Output {} has drained, starting its replacement", name);
+        let (worker_handle, worker_tx) = spawn(&name, &cfg);
+        while let Ok(msg) = rx.recv() {
+            if worker_tx.send(msg).is_err() {
+                break;
+            }
+        }
+        drop(worker_tx);
+        if let Ok(h) = Arc::try_unwrap(worker_handle) {
+            let _ = h.join();
+        }
+    }).unwrap();
+    (Arc::new(handle), tx)
+}
+
+fn spawn_worker(name : &str, kind : &Kind, bufmax : usize) -> Worker {
+    match *kind {
+        Kind::S3(ref c) => s3::spawn(name.to_string(), c.clone(), bufmax),
+        Kind::Es(ref c) => es::spawn(name.to_string(), c.clone(), bufmax),
+        Kind::Stdout(ref c) => stdout::spawn(name.to_string(), c.clone(), bufmax),
+        Kind::Postgres(ref c) => postgres::spawn(name.to_string(), c.clone(), bufmax),
+        Kind::Pachyderm(ref c) => pachyderm::spawn(name.to_string(), c.clone(), bufmax),
+        Kind::Parquet(ref c) => parquet::spawn(name.to_string(), c.clone(), bufmax)
+    }
+}
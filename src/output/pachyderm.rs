@@ -1,143 +1,171 @@
 
-use toml::{Table, Value};
 use std::thread::{self, JoinHandle};
-use std::sync::{Arc, Once, ONCE_INIT }; 
+use std::sync::Arc;
 use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
 use serde_json::Value as JValue;
 use serde_json::ser;
 use std::time::{Duration, Instant};
 use std::sync::mpsc::RecvTimeoutError;
-use std::process::Command;
-use std::path::PathBuf;
-use std::fs::{OpenOptions};
+use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions};
 use std::io::Write;
-use chrono::UTC;
+use std::cmp;
 use nix::unistd;
+use hyper::Url;
+use metrics::OutputMetrics;
+use output::pfs::{Client, PfsError};
+
+pub const DEFAULT_BUFFER_MAX : usize = 10000;
+
+#[derive(RustcDecodable, Debug, Clone, PartialEq)]
+pub struct Config {
+    pub repo : Option<String>,
+    pub repo_branch : Option<String>,
+    pub batch_max_size : Option<usize>,
+    pub batch_min : Option<u64>,
+    pub batch_directory : Option<String>,
+    pub gateway_url : Option<String>,
+    pub request_timeout_secs : Option<u64>,
+    pub retry_max_secs : Option<u64>
+}
 
-static mut HANDLE: Option<Arc<JoinHandle<()>>> = None;
-static mut CHANNEL: Option<SyncSender<Arc<JValue>>> = None;
-static THREAD: Once = ONCE_INIT;
-
-pub fn spawn(cfg: Table) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
-    THREAD.call_once(|| {
-        let bufmax = 
-        if let Some(bm) = cfg.get("buffer_max") {
-            bm.as_integer().unwrap() as usize
-        } else {
-            10000
-        };
-
-        let (tx, rx) = sync_channel(bufmax);
-        let handle = thread::spawn(|| {
-                run(cfg, rx);
-        });
-        unsafe {
-            CHANNEL = Some(tx);
-            HANDLE = Some(Arc::new(handle));
+impl Config {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let batch_directory = self.batch_directory.as_ref().map_or("/var/lib/lout", |d| d.as_str());
+        if !Path::new(batch_directory).is_dir() {
+            errors.push(format!("batch_directory {} is not a directory", batch_directory));
+        }
+        // pachd itself only speaks gRPC, so there is no sensible default to fall back on
+        match self.gateway_url {
+            None => errors.push("a gateway_url for the gRPC-JSON gateway in front of pachd must be given".to_owned()),
+            Some(ref url) => match Url::parse(url) {
+                Ok(ref u) if u.scheme() == "http" => {},
+                Ok(_) => errors.push(format!("gateway_url {} should be an http:// url", url)),
+                Err(e) => errors.push(format!("gateway_url {} is not a valid url : {}", url, e))
+            }
         }
-    });
-    unsafe {
-        (HANDLE.as_ref().unwrap().clone(), CHANNEL.as_ref().unwrap().clone())  
+        errors
     }
 }
 
+pub fn spawn(name: String, cfg: Config, bufmax: usize) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
+    let (tx, rx) = sync_channel(bufmax);
+    let handle = thread::Builder::new().name(name.clone()).spawn(move || {
+        run(name, cfg, rx);
+    }).unwrap();
+    (Arc::new(handle), tx)
+}
 
-fn run(cfg : Table, rx : Receiver<Arc<JValue>>) {
 
-    let default_repo = Value::String("log_events".to_owned());
-    let default_batchdir = Value::String("/var/lib/lout".to_owned());
-    let default_pachyderm_path = Value::String("/opt/pachyderm/bin/pachctl".to_owned());
-    let default_branch = Value::String("master".to_owned());
-    let default_pachd_host = Value::String("localhost".to_owned());
+fn run(name : String, cfg : Config, rx : Receiver<Arc<JValue>>) {
 
-    let repo =      cfg.get("repo").unwrap_or(&default_repo).as_str().unwrap();
-    let repo_branch =      cfg.get("repo_branch").unwrap_or(&default_branch).as_str().unwrap();
-    let batch_max =  cfg.get("batch_max_size")
-        .unwrap_or(&Value::Integer(1024 * 1024 * 1024)).as_integer().unwrap() as usize;
-    let batch_minutes = cfg.get("batch_min").unwrap_or(&Value::Integer(10)).as_integer().unwrap() as u64;
-    let batch_directory = cfg.get("batch_directory").unwrap_or(&default_batchdir).as_str().unwrap();
-    let pachyderm_path = cfg.get("pachyderm_binary_path").unwrap_or(&default_pachyderm_path).as_str().unwrap();
-    let pachd_host = cfg.get("pachd_host").unwrap_or(&default_pachd_host).as_str().unwrap();
+    let repo =            cfg.repo.as_ref().map_or("log_events", |r| r.as_str());
+    let repo_branch =     cfg.repo_branch.as_ref().map_or("master", |b| b.as_str());
+    let batch_max =       cfg.batch_max_size.unwrap_or(1024 * 1024 * 1024);
+    let batch_minutes =   cfg.batch_min.unwrap_or(10);
+    let batch_directory = cfg.batch_directory.as_ref().map_or("/var/lib/lout", |d| d.as_str());
+    let pachd_url =       cfg.gateway_url.clone().unwrap();
+    let timeout =         Duration::from_secs(cfg.request_timeout_secs.unwrap_or(60));
+    let retry_max =       Duration::from_secs(cfg.retry_max_secs.unwrap_or(300));
+    let metrics = OutputMetrics::new(&name);
+    let client = Client::new(&pachd_url, timeout);
 
     let batch_dur = Duration::from_secs(batch_minutes * 60);
     let mut batchpath = PathBuf::from(batch_directory);
-    batchpath.push("pachyderm_batch");
+    batchpath.push(format!("{}.pachyderm_batch", name));
 
     println!("Creating batch file at {:?}", batchpath);
 
     let mut batchfile = OpenOptions::new().read(true).append(true).create(true).open(batchpath.clone()).unwrap();
 
-    let mut failcount = 0;
-    let mut num_bytes = 0usize;
+    // a batch left over from before a restart goes out with the next commit
+    let mut num_bytes = batchfile.metadata().map(|m| m.len() as usize).unwrap_or(0);
+    let mut count = 0;
     let to = Duration::from_millis(100);
     let mut last = Instant::now();
-    let mut msgstr;
+    let mut backoff = Duration::from_secs(1);
+    let mut retry_at = Instant::now();
     
     let mut hostbytes = [0u8; 128];
     unistd::gethostname(&mut hostbytes).unwrap();
     let hostname = String::from_utf8(hostbytes.iter().take_while(|c| **c != 0).cloned().collect()).unwrap();
 
-    println!("batching files as host : {}", hostname);
+    println!("{} committing to {} on {} as host : {}", name, repo, pachd_url, hostname);
 
-    while failcount < 10 {
+    let mut running = true;
+    loop {
         match rx.recv_timeout(to) {
             Ok(msg) => { 
-                msgstr = ser::to_string(&msg).unwrap_or(String::new());
-                msgstr.push('\n')
+                metrics.depth.dec();
+                let mut msgstr = ser::to_string(&msg).unwrap_or(String::new());
+                msgstr.push('\n');
+                match batchfile.write_all(msgstr.as_bytes()) {
+                    Ok(_) => {
+                        num_bytes += msgstr.len();
+                        count += 1;
+                    },
+                    Err(e) => {
+                        metrics.errors.inc();
+                        error!("{} failed to add a message to its batch {:?} : {}", name, batchpath, e);
+                        // drop whatever part of the message made it in, so the batch stays whole lines
+                        if let Err(e) = batchfile.set_len(num_bytes as u64) {
+                            error!("{} failed to truncate its batch {:?} : {}", name, batchpath, e);
+                        }
+                    }
+                }
             },
             Err(RecvTimeoutError::Disconnected) => { 
                     error!("Main loop channel disconnected. Shutting down.");
-                    break;
+                    running = false;
             },
-            Err(RecvTimeoutError::Timeout) => continue
+            Err(RecvTimeoutError::Timeout) => {}
         }
-        if last.elapsed() > batch_dur || (num_bytes + msgstr.len()) > batch_max {
-
-            if num_bytes > 0 {
-                println!("running batch : pachctl put-file {} {} {} -c -f {}", repo, repo_branch, &hostname, batchpath.to_str().unwrap());
-                while failcount < 10 {
-                    let result = Command::new(pachyderm_path)
-                        .arg("put-file")
-                        .arg(repo)
-                        .arg(repo_branch)
-                        .arg(&hostname)
-                        .arg("-c")
-                        .arg("-f")
-                        .arg(batchpath.to_str().unwrap())
-                        .output()
-                        .unwrap();
-
-                    if !result.status.success() {
-                        error!("batch operation {} failed with status code {}.  stderr={},  stdout={}",
-                                pachyderm_path, result.status,
-                                String::from_utf8(result.stderr).unwrap(),
-                                String::from_utf8(result.stdout).unwrap());
-                        failcount += 1;
-                    }
-                    else {
-                        failcount = 0;
-                        println!("batch operation succeeded");
-                        break
+
+        // messages keep being added to the batch while a failed commit waits to be retried,
+        // and on shutdown the final batch goes out immediately
+        let due = !running || last.elapsed() > batch_dur || num_bytes > batch_max;
+        if due && num_bytes > 0 && (!running || Instant::now() >= retry_at) {
+            let op_start = Instant::now();
+            // the batch is read from disk as it is sent, rather than held in memory
+            let committed = File::open(&batchpath)
+                .map_err(|e| PfsError::Transient(format!("opening the batch {:?} failed : {}", batchpath, e)))
+                .and_then(|f| client.commit_file(repo, repo_branch, &hostname, f));
+            match committed {
+                Ok(commit) => {
+                    info!("{} committed {} bytes to {}@{}", name, num_bytes, repo, commit.id);
+                    backoff = Duration::from_secs(1);
+                },
+                Err(ref e) if e.is_transient() => {
+                    metrics.errors.inc();
+                    retry_at = Instant::now() + backoff;
+                    error!("{} failed to commit its batch, retrying in {:?} : {}", name, backoff, e);
+                    backoff = cmp::min(backoff * 2, retry_max);
+                    if running {
+                        continue;
+                    } else {
+                        error!("{} is shutting down, leaving its batch in {:?} for the next start", name, batchpath);
+                        break;
                     }
+                },
+                Err(e) => {
+                    metrics.errors.inc();
+                    error!("{} discarding a batch of {} bytes which pachd refused : {}", name, num_bytes, e);
                 }
-                if failcount >= 10 {
-                    println!("batch operation failed after 10 tries");
-                }
-                batchfile.set_len(0).unwrap();
-                num_bytes = 0;
             }
+            metrics.batch_size.observe(count as f64);
+            metrics.batch_seconds.observe_duration(op_start.elapsed());
+            batchfile.set_len(0).unwrap();
+            num_bytes = 0;
+            count = 0;
+            last = Instant::now();
+        } else if due && num_bytes == 0 {
             last = Instant::now();
         }
-        batchfile.write(msgstr.as_bytes()).unwrap();
-        num_bytes += msgstr.len();
+        if !running {
+            break;
+        }
     }
 
-    if failcount >= 10 {
-        error!("Failed 10 times in a row. Giving up");
-    } else {
-        error!("Pachyderm output shutting down gracefully");
-    }
+    error!("Pachyderm output {} shutting down gracefully", name);
 }
-
-
-
@@ -0,0 +1,370 @@
+use std::thread::{self, JoinHandle};
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read, Write, BufWriter};
+use std::path::{Path, PathBuf, Component};
+use std::cmp;
+use serde_json::Value as JValue;
+use rusoto_core::{DefaultCredentialsProvider, Region, default_tls_client};
+use rusoto_s3::S3Client;
+use chrono::UTC;
+use metrics::OutputMetrics;
+use output::s3;
+use output::key_template::KeyTemplate;
+use output::translator::{self, Mapping};
+use output::parquet_file::{ParquetWriter, ColumnType, Codec};
+
+pub const DEFAULT_BUFFER_MAX : usize = 1_000_000;
+pub const DEFAULT_KEY_TEMPLATE : &'static str = "{timestamp}.parquet";
+
+#[derive(RustcDecodable, Debug, Clone, PartialEq)]
+pub struct Config {
+    pub json_schema : Option<String>,
+    pub batch_directory : Option<String>,
+    pub directory : Option<String>,
+    pub bucket : Option<String>,
+    pub region : Option<String>,
+    pub endpoint : Option<String>,
+    pub key_template : Option<String>,
+    pub max_partitions : Option<usize>,
+    pub compression : Option<String>,
+    pub file_max_bytes : Option<u64>,
+    pub file_secs : Option<u64>,
+    pub row_group_bytes : Option<u64>,
+    pub multipart_part_size : Option<u64>,
+    pub retry_max_secs : Option<u64>
+}
+
+/// The columns of the files for one record type, in the order of the mapping's fields
+fn columns(mapping : &Mapping) -> Result<Vec<(String, ColumnType)>, String> {
+    mapping.fields.iter().map(|(name, field)| {
+        ColumnType::parse(field.typename.as_ref().map(|t| t.as_str()))
+            .map(|ty| (name.clone(), ty))
+            .map_err(|e| format!("field {} : {}", name, e))
+    }).collect()
+}
+
+impl Config {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let schemafile = self.json_schema.as_ref().map_or("/etc/lout/schema.json", |s| s.as_str());
+        match translator::fetch_schema(Path::new(schemafile)) {
+            Ok(schema) => for (record_type, mapping) in schema.mappings.iter() {
+                if let Err(e) = columns(mapping) {
+                    errors.push(format!("json_schema {} mapping {} : {}", schemafile, record_type, e));
+                }
+            },
+            Err(e) => errors.push(format!("json_schema {} could not be loaded : {}", schemafile, e))
+        }
+        let batch_directory = self.batch_directory.as_ref().map_or("/var/lib/lout", |d| d.as_str());
+        if !Path::new(batch_directory).is_dir() {
+            errors.push(format!("batch_directory {} is not a directory", batch_directory));
+        }
+        match (&self.directory, &self.bucket) {
+            (&Some(ref d), &None) => if !Path::new(d).is_dir() {
+                errors.push(format!("directory {} is not a directory", d));
+            },
+            (&None, &Some(_)) => if let Err(e) = self.region() {
+                errors.push(e);
+            },
+            _ => errors.push("exactly one of directory or bucket should be given".to_owned())
+        }
+        if let Err(e) = KeyTemplate::parse(self.key_template.as_ref().map_or(DEFAULT_KEY_TEMPLATE, |t| t.as_str())) {
+            errors.push(e);
+        }
+        if let Err(e) = Codec::parse(self.compression.as_ref().map_or("snappy", |c| c.as_str())) {
+            errors.push(e);
+        }
+        if self.multipart_part_size.unwrap_or(s3::DEFAULT_PART_SIZE) < s3::MIN_PART_SIZE {
+            errors.push(format!("multipart_part_size must be at least {} bytes", s3::MIN_PART_SIZE));
+        }
+        errors
+    }
+
+    fn region(&self) -> Result<Region, String> {
+        s3::region(self.region.as_ref().map(|r| r.as_str()), self.endpoint.as_ref().map(|e| e.as_str()))
+    }
+}
+
+pub fn spawn(name: String, cfg: Config, bufmax: usize) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
+    let (tx, rx) = sync_channel(bufmax);
+    let handle = thread::Builder::new().name(name.clone()).spawn(move || {
+        run(name, cfg, rx);
+    }).unwrap();
+    (Arc::new(handle), tx)
+}
+
+/// Where finished files go
+enum Destination {
+    Directory(PathBuf),
+    Bucket { region : Region, bucket : String, part_size : u64 }
+}
+
+/// The file being written for one record type and partition of the key template
+struct OpenFile {
+    writer : ParquetWriter<BufWriter<File>>,
+    path : PathBuf,
+    opened : Instant
+}
+
+/// A complete file, which stays in the batch directory until it has been delivered to `key`
+struct Finished {
+    path : PathBuf,
+    key : String,
+    rows : i64
+}
+
+fn key_path(file : &Path) -> PathBuf {
+    file.with_extension("parquetkey")
+}
+
+/// Writes the footer of `file`, then moves it aside with the key it will be delivered to
+fn finish(file : OpenFile, partition : &str) -> io::Result<Finished> {
+    let rows = file.writer.num_rows();
+    file.writer.finish()?;
+    let key = KeyTemplate::key(partition, UTC::now());
+    File::create(key_path(&file.path)).and_then(|mut f| f.write_all(key.as_bytes()))?;
+    let finished = file.path.with_extension("parquet");
+    fs::rename(&file.path, &finished)?;
+    Ok(Finished { path : finished, key : key, rows : rows })
+}
+
+fn finish_all(name : &str, open : &mut HashMap<String, OpenFile>, partitions : Vec<String>, finished : &mut VecDeque<Finished>) {
+    for partition in partitions {
+        if let Some(file) = open.remove(&partition) {
+            let path = file.path.clone();
+            match finish(file, &partition) {
+                Ok(f) => finished.push_back(f),
+                Err(e) => {
+                    error!("{} could not finish {:?}, discarding it : {}", name, path, e);
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+    }
+}
+
+/// Finds the files this output finished but had not delivered when it last stopped, oldest first
+fn recover(name : &str, dir : &Path) -> Vec<Finished> {
+    let mut found = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("{} could not look for files left in {:?} : {}", name, dir, e);
+            return found;
+        }
+    };
+    let prefix = format!("{}.", name);
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let ours = path.file_stem().and_then(|s| s.to_str())
+            .map_or(false, |s| s.starts_with(&prefix) && !s[prefix.len()..].contains('.'));
+        if !ours {
+            continue;
+        }
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("parquet") => {
+                let mut key = String::new();
+                match File::open(key_path(&path)).and_then(|mut f| f.read_to_string(&mut key)) {
+                    Ok(_) => found.push(Finished { path : path, key : key, rows : 0 }),
+                    Err(e) => error!("{} cannot deliver {:?} without its key, leaving it : {}", name, path, e)
+                }
+            },
+            // a file has no footer until it is finished, so one left open can't be read
+            Some("part") => {
+                error!("{} discarding {:?}, which was not finished before lout stopped", name, path);
+                let _ = fs::remove_file(&path);
+            },
+            _ => {}
+        }
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    found
+}
+
+fn move_file(from : &Path, to : &Path) -> Result<(), String> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("could not create {:?} : {}", parent, e))?;
+    }
+    // the batch directory may be on another filesystem
+    fs::rename(from, to).or_else(|_| fs::copy(from, to).and_then(|_| fs::remove_file(from)))
+        .map_err(|e| format!("could not move {:?} to {:?} : {}", from, to, e))
+}
+
+/// The path for `key` under `dir`, refusing keys which would land anywhere else
+fn key_in(dir : &Path, key : &str) -> Result<PathBuf, String> {
+    if Path::new(key).components().all(|c| match c { Component::Normal(_) => true, _ => false }) {
+        Ok(dir.join(key))
+    } else {
+        Err(format!("key {} would be written outside {:?}", key, dir))
+    }
+}
+
+/// Delivers finished files oldest first, returning false, and leaving the rest for later, as
+/// soon as one fails.  A file whose key can never be delivered is dropped.
+fn deliver(name : &str, dest : &Destination, finished : &mut VecDeque<Finished>, metrics : &OutputMetrics) -> bool {
+    let client = match *dest {
+        Destination::Bucket { ref region, .. } => match DefaultCredentialsProvider::new() {
+            Ok(dcp) => Some(S3Client::new(default_tls_client().unwrap(), dcp, region.clone())),
+            Err(err) => {
+                metrics.errors.inc();
+                error!("Failed to discover AWS credentials {}", err);
+                return false;
+            }
+        },
+        Destination::Directory(_) => None
+    };
+    while let Some(f) = finished.pop_front() {
+        let op_start = Instant::now();
+        let result = match (dest, client.as_ref()) {
+            (&Destination::Bucket { ref bucket, part_size, .. }, Some(client)) =>
+                s3::put_file(client, bucket, &f.key, &f.path, None, part_size).map(|_| { let _ = fs::remove_file(&f.path); }),
+            (&Destination::Directory(ref dir), _) => match key_in(dir, &f.key) {
+                Ok(to) => move_file(&f.path, &to),
+                Err(err) => {
+                    // trying again won't change where the key points, so the file is dropped rather
+                    // than holding up the ones behind it
+                    metrics.errors.inc();
+                    error!("{} discarding {:?} : {}", name, f.path, err);
+                    let _ = fs::remove_file(&f.path);
+                    let _ = fs::remove_file(key_path(&f.path));
+                    continue;
+                }
+            },
+            _ => unreachable!()
+        };
+        if let Err(err) = result {
+            metrics.errors.inc();
+            error!("{} failed to deliver {} : {}", name, f.key, err);
+            finished.push_front(f);
+            return false;
+        }
+        let _ = fs::remove_file(key_path(&f.path));
+
+        let op_duration = op_start.elapsed();
+        if f.rows > 0 {
+            metrics.batch_size.observe(f.rows as f64);
+        }
+        metrics.batch_seconds.observe_duration(op_duration);
+        info!("Delivery of {} took {:?}", f.key, op_duration);
+    }
+    true
+}
+
+fn run(name : String, cfg : Config, rx : Receiver<Arc<JValue>>) {
+
+    let schemafile      = cfg.json_schema.as_ref().map_or("/etc/lout/schema.json", |s| s.as_str());
+    let schema          = translator::fetch_schema(Path::new(schemafile)).unwrap();
+    let batch_directory = cfg.batch_directory.as_ref().map_or("/var/lib/lout", |d| d.as_str());
+    let template        = KeyTemplate::parse(cfg.key_template.as_ref().map_or(DEFAULT_KEY_TEMPLATE, |t| t.as_str())).unwrap();
+    let max_partitions  = cfg.max_partitions.unwrap_or(20);
+    let codec           = Codec::parse(cfg.compression.as_ref().map_or("snappy", |c| c.as_str())).unwrap();
+    let file_max_bytes  = cfg.file_max_bytes.unwrap_or(128 * 1024 * 1024);
+    let file_dur        = Duration::from_secs(cfg.file_secs.unwrap_or(300));
+    let row_group_bytes = cfg.row_group_bytes.unwrap_or(8 * 1024 * 1024);
+    let retry_max       = Duration::from_secs(cfg.retry_max_secs.unwrap_or(300));
+    let dest = match cfg.bucket {
+        Some(ref bucket) => Destination::Bucket {
+            region : cfg.region().unwrap(),
+            bucket : bucket.clone(),
+            part_size : cmp::max(cfg.multipart_part_size.unwrap_or(s3::DEFAULT_PART_SIZE), s3::MIN_PART_SIZE)
+        },
+        None => Destination::Directory(PathBuf::from(cfg.directory.as_ref().map_or("/var/lib/lout/parquet", |d| d.as_str())))
+    };
+    let metrics = OutputMetrics::new(&name);
+    let hostname = s3::hostname();
+
+    println!("Writing parquet files for {} in {}", name, batch_directory);
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut finished : VecDeque<Finished> = recover(&name, Path::new(batch_directory)).into_iter().collect();
+    if !finished.is_empty() {
+        println!("{} found {} files to deliver from before it restarted", name, finished.len());
+    }
+    let mut open = HashMap::<String, OpenFile>::new();
+    let mut next_file = 0_u64;
+
+    let to = Duration::from_millis(100);
+    let mut backoff = Duration::from_secs(1);
+    let mut retry_at = Instant::now();
+    let mut running = true;
+    loop {
+        match rx.recv_timeout(to) {
+            Ok(msg) => {
+                metrics.depth.dec();
+                // only messages with a mapping in the schema have columns to go in
+                if let Some((record_type, mapping)) = schema.mapping_for(&msg) {
+                    // each record type has its own columns, so its files go under a prefix of their own
+                    let partition = format!("{}/{}", record_type, template.partition(&msg, &name, &hostname));
+                    if !open.contains_key(&partition) && open.len() >= max_partitions {
+                        // too many files are open, so finish them early to make room
+                        let all = open.keys().cloned().collect();
+                        finish_all(&name, &mut open, all, &mut finished);
+                    }
+                    if !open.contains_key(&partition) {
+                        let mut path = PathBuf::from(batch_directory);
+                        path.push(format!("{}.{}-{:010}.part", name, started, next_file));
+                        next_file += 1;
+                        let writer = File::create(&path)
+                            .and_then(|f| ParquetWriter::new(BufWriter::new(f), columns(mapping).unwrap(), codec));
+                        match writer {
+                            Ok(w) => { open.insert(partition.clone(), OpenFile { writer : w, path : path, opened : Instant::now() }); },
+                            Err(e) => {
+                                metrics.errors.inc();
+                                error!("{} could not create {:?} : {}", name, path, e);
+                            }
+                        }
+                    }
+                    let mut failed = false;
+                    if let Some(f) = open.get_mut(&partition) {
+                        let row : Vec<Option<&JValue>> = mapping.fields.values().map(|field| msg.pointer(&field.path)).collect();
+                        f.writer.write_row(&row);
+                        if f.writer.buffered_bytes() as u64 >= row_group_bytes {
+                            if let Err(e) = f.writer.flush_row_group() {
+                                error!("{} could not write to {:?}, discarding it : {}", name, f.path, e);
+                                failed = true;
+                            }
+                        }
+                    }
+                    if failed {
+                        metrics.errors.inc();
+                        if let Some(f) = open.remove(&partition) {
+                            let _ = fs::remove_file(&f.path);
+                        }
+                    }
+                }
+            },
+            Err(RecvTimeoutError::Disconnected) => { running = false; error!("Main loop channel disconnected. Shutting down."); }
+            Err(RecvTimeoutError::Timeout) => {},
+        }
+
+        // files are rolled by size and age, and all of them on shutdown
+        let due : Vec<String> = open.iter().filter(|&(_, f)| {
+            !running || f.opened.elapsed() > file_dur || f.writer.bytes_written() + f.writer.buffered_bytes() as u64 >= file_max_bytes
+        }).map(|(p, _)| p.clone()).collect();
+        if !due.is_empty() {
+            finish_all(&name, &mut open, due, &mut finished);
+        }
+
+        if !finished.is_empty() && (!running || Instant::now() >= retry_at) {
+            if deliver(&name, &dest, &mut finished, &metrics) {
+                backoff = Duration::from_secs(1);
+            } else {
+                retry_at = Instant::now() + backoff;
+                error!("{} has {} files waiting to be delivered, retrying in {:?}", name, finished.len(), backoff);
+                backoff = cmp::min(backoff * 2, retry_max);
+            }
+        }
+
+        if !running {
+            if !finished.is_empty() {
+                error!("{} is shutting down, leaving {} files in {} for the next start", name, finished.len(), batch_directory);
+            }
+            break;
+        }
+    }
+
+    error!("Parquet output {} shutting down gracefully", name);
+}
@@ -0,0 +1,493 @@
+use std::io::{self, Write};
+use std::mem;
+use flate2::write::GzEncoder;
+use flate2::Compression as GzLevel;
+use serde_json::Value as JValue;
+use chrono::{DateTime, Timelike};
+use snap;
+
+const MAGIC : &'static [u8] = b"PAR1";
+
+// parquet.thrift enum values
+const PAGE_TYPE_DATA : i32 = 0;
+const ENCODING_PLAIN : i32 = 0;
+const ENCODING_RLE : i32 = 3;
+const REPETITION_OPTIONAL : i32 = 1;
+
+/// The type of a column, from the `typename` of a field in the translator schema
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Boolean,
+    Int64,
+    Double,
+    Utf8,
+    /// GELF timestamps, which are seconds since the epoch, stored as milliseconds
+    TimestampMillis
+}
+
+impl ColumnType {
+    /// Fields without a `typename` are strings
+    pub fn parse(typename : Option<&str>) -> Result<ColumnType, String> {
+        match typename.map(|t| t.to_lowercase()) {
+            None => Ok(ColumnType::Utf8),
+            Some(t) => match t.as_str() {
+                "string" | "text" | "varchar" => Ok(ColumnType::Utf8),
+                "bool" | "boolean" => Ok(ColumnType::Boolean),
+                "int" | "integer" | "bigint" | "long" => Ok(ColumnType::Int64),
+                "float" | "double" | "real" | "numeric" => Ok(ColumnType::Double),
+                "timestamp" | "timestamptz" => Ok(ColumnType::TimestampMillis),
+                _ => Err(format!("typename {} should be one of string, boolean, integer, double or timestamp", t))
+            }
+        }
+    }
+
+    fn physical_type(&self) -> i32 {
+        match *self {
+            ColumnType::Boolean => 0,
+            ColumnType::Int64 | ColumnType::TimestampMillis => 2,
+            ColumnType::Double => 5,
+            ColumnType::Utf8 => 6
+        }
+    }
+
+    fn converted_type(&self) -> Option<i32> {
+        match *self {
+            ColumnType::Utf8 => Some(0),
+            ColumnType::TimestampMillis => Some(9),
+            _ => None
+        }
+    }
+}
+
+/// How the pages of a file are compressed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Uncompressed,
+    Snappy,
+    Gzip
+}
+
+impl Codec {
+    pub fn parse(c : &str) -> Result<Codec, String> {
+        match c {
+            "none" => Ok(Codec::Uncompressed),
+            "snappy" => Ok(Codec::Snappy),
+            "gzip" => Ok(Codec::Gzip),
+            c => Err(format!("compression {} should be one of snappy, gzip or none", c))
+        }
+    }
+
+    fn id(&self) -> i32 {
+        match *self {
+            Codec::Uncompressed => 0,
+            Codec::Snappy => 1,
+            Codec::Gzip => 2
+        }
+    }
+
+    fn compress(&self, data : Vec<u8>) -> io::Result<Vec<u8>> {
+        match *self {
+            Codec::Uncompressed => Ok(data),
+            // parquet uses raw snappy blocks rather than the framed format
+            Codec::Snappy => snap::Encoder::new().compress_vec(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())),
+            Codec::Gzip => {
+                let mut gze = GzEncoder::new(Vec::with_capacity(data.len() / 4), GzLevel::Default);
+                gze.write_all(&data)?;
+                gze.finish()
+            }
+        }
+    }
+}
+
+/// Just enough of thrift's compact protocol to write parquet's page headers and footer
+struct Thrift {
+    buf : Vec<u8>,
+    // the last field id written in each struct which is open
+    last : Vec<i16>
+}
+
+const T_I32 : u8 = 5;
+const T_I64 : u8 = 6;
+const T_BINARY : u8 = 8;
+const T_LIST : u8 = 9;
+const T_STRUCT : u8 = 12;
+
+impl Thrift {
+    fn new() -> Thrift {
+        Thrift { buf : Vec::new(), last : vec![0] }
+    }
+
+    fn varint(&mut self, mut v : u64) {
+        while v >= 0x80 {
+            self.buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.buf.push(v as u8);
+    }
+
+    fn zigzag(&mut self, v : i64) {
+        self.varint(((v << 1) ^ (v >> 63)) as u64);
+    }
+
+    fn field(&mut self, id : i16, ty : u8) {
+        let delta = id - *self.last.last().unwrap();
+        if delta > 0 && delta <= 15 {
+            self.buf.push(((delta as u8) << 4) | ty);
+        } else {
+            self.buf.push(ty);
+            self.zigzag(id as i64);
+        }
+        *self.last.last_mut().unwrap() = id;
+    }
+
+    fn i32(&mut self, id : i16, v : i32) {
+        self.field(id, T_I32);
+        self.zigzag(v as i64);
+    }
+
+    fn i64(&mut self, id : i16, v : i64) {
+        self.field(id, T_I64);
+        self.zigzag(v);
+    }
+
+    fn binary(&mut self, id : i16, v : &[u8]) {
+        self.field(id, T_BINARY);
+        self.bytes(v);
+    }
+
+    fn bytes(&mut self, v : &[u8]) {
+        self.varint(v.len() as u64);
+        self.buf.extend_from_slice(v);
+    }
+
+    fn list(&mut self, id : i16, elem : u8, len : usize) {
+        self.field(id, T_LIST);
+        if len < 15 {
+            self.buf.push(((len as u8) << 4) | elem);
+        } else {
+            self.buf.push(0xf0 | elem);
+            self.varint(len as u64);
+        }
+    }
+
+    /// Starts a struct, either as field `id` or, without one, as an element of a list
+    fn begin(&mut self, id : Option<i16>) {
+        if let Some(id) = id {
+            self.field(id, T_STRUCT);
+        }
+        self.last.push(0);
+    }
+
+    fn end(&mut self) {
+        self.buf.push(0);
+        self.last.pop();
+    }
+}
+
+fn le_u32(v : u32) -> [u8; 4] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+}
+
+/// Packs booleans a bit each, least significant bit first, as both plain booleans and the
+/// bit-packed runs of the RLE hybrid encoding expect
+fn bitpack(bits : &[bool]) -> Vec<u8> {
+    let mut packed = vec![0u8; (bits.len() + 7) / 8];
+    for (i, b) in bits.iter().enumerate() {
+        if *b {
+            packed[i / 8] |= 1 << (i % 8);
+        }
+    }
+    packed
+}
+
+/// `v` as an integer, if it is a number or string with no fractional part
+pub fn integral(v : &JValue) -> Option<i64> {
+    v.as_i64()
+        .or_else(|| v.as_f64().and_then(|f| if f.fract() == 0.0 && f.abs() < i64::max_value() as f64 { Some(f as i64) } else { None }))
+        .or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+}
+
+struct Column {
+    name : String,
+    ty : ColumnType,
+    defined : Vec<bool>,
+    // plain encoded values, or for a boolean column the values themselves until they are packed
+    values : Vec<u8>,
+    bools : Vec<bool>
+}
+
+impl Column {
+    fn push(&mut self, v : Option<&JValue>) {
+        let defined = match (self.ty, v) {
+            (_, None) | (_, Some(&JValue::Null)) => false,
+            (ColumnType::Boolean, Some(v)) => match *v {
+                JValue::Bool(b) => { self.bools.push(b); true },
+                JValue::String(ref s) if s == "true" || s == "false" => { self.bools.push(s == "true"); true },
+                _ => false
+            },
+            (ColumnType::Int64, Some(v)) => match integral(v) {
+                Some(i) => { self.plain_i64(i); true },
+                None => false
+            },
+            (ColumnType::Double, Some(v)) => match v.as_f64().or_else(|| v.as_str().and_then(|s| s.parse().ok())) {
+                Some(f) => { self.plain_i64(unsafe { mem::transmute::<f64, i64>(f) }); true },
+                None => false
+            },
+            (ColumnType::TimestampMillis, Some(v)) => {
+                let millis = match v.as_f64() {
+                    Some(secs) => Some((secs * 1000.0) as i64),
+                    None => v.as_str().and_then(|s| DateTime::parse_from_rfc3339(s).ok())
+                              .map(|t| t.timestamp() * 1000 + (t.nanosecond() / 1_000_000) as i64)
+                };
+                match millis {
+                    Some(m) => { self.plain_i64(m); true },
+                    None => false
+                }
+            },
+            (ColumnType::Utf8, Some(v)) => {
+                let s = match *v {
+                    JValue::String(ref s) => s.clone(),
+                    ref v => v.to_string()
+                };
+                self.values.extend_from_slice(&le_u32(s.len() as u32));
+                self.values.extend_from_slice(s.as_bytes());
+                true
+            }
+        };
+        self.defined.push(defined);
+    }
+
+    fn plain_i64(&mut self, v : i64) {
+        for i in 0..8 {
+            self.values.push((v >> (i * 8)) as u8);
+        }
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        self.values.len() + (self.bools.len() + self.defined.len()) / 8
+    }
+
+    /// The page holding everything pushed since the last page : the definition levels, as
+    /// bit-packed runs behind their length, followed by the values which are defined
+    fn take_page(&mut self) -> Vec<u8> {
+        let levels = bitpack(&self.defined);
+        let mut hybrid = Thrift::new();
+        hybrid.varint(((levels.len() as u64) << 1) | 1);
+        hybrid.buf.extend_from_slice(&levels);
+        let mut page = Vec::with_capacity(4 + hybrid.buf.len() + self.values.len());
+        page.extend_from_slice(&le_u32(hybrid.buf.len() as u32));
+        page.extend_from_slice(&hybrid.buf);
+        if self.ty == ColumnType::Boolean {
+            page.extend_from_slice(&bitpack(&self.bools));
+        } else {
+            page.extend_from_slice(&self.values);
+        }
+        self.defined.clear();
+        self.values.clear();
+        self.bools.clear();
+        page
+    }
+}
+
+struct ChunkMeta {
+    num_values : i64,
+    offset : i64,
+    uncompressed : i64,
+    compressed : i64
+}
+
+struct RowGroupMeta {
+    chunks : Vec<ChunkMeta>,
+    num_rows : i64
+}
+
+/// Writes a parquet file of flat, optional columns to `W`, a row group at a time.  Rows are
+/// buffered in memory until `flush_row_group`, and the footer is written by `finish`.
+pub struct ParquetWriter<W : Write> {
+    out : W,
+    offset : u64,
+    codec : Codec,
+    columns : Vec<Column>,
+    rows : i64,
+    row_groups : Vec<RowGroupMeta>
+}
+
+impl<W : Write> ParquetWriter<W> {
+    pub fn new(mut out : W, columns : Vec<(String, ColumnType)>, codec : Codec) -> io::Result<ParquetWriter<W>> {
+        out.write_all(MAGIC)?;
+        let columns = columns.into_iter().map(|(name, ty)| {
+            Column { name : name, ty : ty, defined : Vec::new(), values : Vec::new(), bools : Vec::new() }
+        }).collect();
+        Ok(ParquetWriter { out : out, offset : MAGIC.len() as u64, codec : codec, columns : columns, rows : 0, row_groups : Vec::new() })
+    }
+
+    /// Adds a row, with a value or `None` for each column in the order they were given
+    pub fn write_row(&mut self, row : &[Option<&JValue>]) {
+        assert_eq!(row.len(), self.columns.len());
+        for (c, v) in self.columns.iter_mut().zip(row.iter()) {
+            c.push(*v);
+        }
+        self.rows += 1;
+    }
+
+    /// Roughly how much memory the rows waiting for the next row group take
+    pub fn buffered_bytes(&self) -> usize {
+        self.columns.iter().map(|c| c.buffered_bytes()).sum()
+    }
+
+    /// How much of the file has been written so far
+    pub fn bytes_written(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn num_rows(&self) -> i64 {
+        self.row_groups.iter().map(|g| g.num_rows).sum::<i64>() + self.rows
+    }
+
+    /// Writes the buffered rows as a row group, with one data page for each column
+    pub fn flush_row_group(&mut self) -> io::Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let mut chunks = Vec::with_capacity(self.columns.len());
+        for c in self.columns.iter_mut() {
+            let page = c.take_page();
+            let uncompressed = page.len();
+            let page = self.codec.compress(page)?;
+            let mut header = Thrift::new();
+            header.i32(1, PAGE_TYPE_DATA);
+            header.i32(2, uncompressed as i32);
+            header.i32(3, page.len() as i32);
+            header.begin(Some(5));
+            header.i32(1, self.rows as i32);
+            header.i32(2, ENCODING_PLAIN);
+            header.i32(3, ENCODING_RLE);
+            header.i32(4, ENCODING_RLE);
+            header.end();
+            header.end();
+            self.out.write_all(&header.buf)?;
+            self.out.write_all(&page)?;
+            chunks.push(ChunkMeta {
+                num_values : self.rows,
+                offset : self.offset as i64,
+                uncompressed : (header.buf.len() + uncompressed) as i64,
+                compressed : (header.buf.len() + page.len()) as i64
+            });
+            self.offset += (header.buf.len() + page.len()) as u64;
+        }
+        self.row_groups.push(RowGroupMeta { chunks : chunks, num_rows : self.rows });
+        self.rows = 0;
+        Ok(())
+    }
+
+    /// Writes the last row group and the footer, giving back the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_row_group()?;
+        let mut meta = Thrift::new();
+        meta.i32(1, 1);
+        meta.list(2, T_STRUCT, self.columns.len() + 1);
+        meta.begin(None);
+        meta.binary(4, b"schema");
+        meta.i32(5, self.columns.len() as i32);
+        meta.end();
+        for c in self.columns.iter() {
+            meta.begin(None);
+            meta.i32(1, c.ty.physical_type());
+            meta.i32(3, REPETITION_OPTIONAL);
+            meta.binary(4, c.name.as_bytes());
+            if let Some(converted) = c.ty.converted_type() {
+                meta.i32(6, converted);
+            }
+            meta.end();
+        }
+        meta.i64(3, self.num_rows());
+        meta.list(4, T_STRUCT, self.row_groups.len());
+        for g in self.row_groups.iter() {
+            meta.begin(None);
+            meta.list(1, T_STRUCT, g.chunks.len());
+            for (chunk, c) in g.chunks.iter().zip(self.columns.iter()) {
+                meta.begin(None);
+                meta.i64(2, chunk.offset);
+                meta.begin(Some(3));
+                meta.i32(1, c.ty.physical_type());
+                meta.list(2, T_I32, 2);
+                meta.zigzag(ENCODING_PLAIN as i64);
+                meta.zigzag(ENCODING_RLE as i64);
+                meta.list(3, T_BINARY, 1);
+                meta.bytes(c.name.as_bytes());
+                meta.i32(4, self.codec.id());
+                meta.i64(5, chunk.num_values);
+                meta.i64(6, chunk.uncompressed);
+                meta.i64(7, chunk.compressed);
+                meta.i64(9, chunk.offset);
+                meta.end();
+                meta.end();
+            }
+            meta.i64(2, g.chunks.iter().map(|c| c.uncompressed).sum());
+            meta.i64(3, g.num_rows);
+            meta.end();
+        }
+        meta.binary(6, b"lout");
+        meta.end();
+        self.out.write_all(&meta.buf)?;
+        self.out.write_all(&le_u32(meta.buf.len() as u32))?;
+        self.out.write_all(MAGIC)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ParquetWriter, ColumnType, Codec, Thrift, bitpack, integral};
+    use serde_json::de;
+    use serde_json::Value as JValue;
+
+#[test]
+    fn parquet_thrift_and_layout() {
+        let mut t = Thrift::new();
+        t.i32(1, 0);
+        t.i32(2, -1);
+        t.i64(20, 300);
+        t.end();
+        // short field headers, zigzag varints, and a long form header for a delta over 15
+        assert_eq!(t.buf, vec![0x15, 0x00, 0x15, 0x01, 0x06, 0x28, 0xd8, 0x04, 0x00]);
+        assert_eq!(bitpack(&[true, false, true, true, false, false, false, false, true]), vec![0x0d, 0x01]);
+        let ints : Vec<Option<i64>> = ["3", "2.0", "1.5", "\"7\"", "\"7.5\"", "true"].iter()
+            .map(|v| integral(&de::from_str(v).unwrap())).collect();
+        assert_eq!(ints, vec![Some(3), Some(2), None, Some(7), None, None]);
+
+        let rows : Vec<JValue> = vec![
+            de::from_str(r#"{"host": "a", "level": 3, "timestamp": 1489000000.25, "ok": true}"#).unwrap(),
+            de::from_str(r#"{"host": "b", "level": "x"}"#).unwrap()
+        ];
+        let columns = vec![("host".to_owned(), ColumnType::Utf8), ("level".to_owned(), ColumnType::Int64),
+                           ("timestamp".to_owned(), ColumnType::TimestampMillis), ("ok".to_owned(), ColumnType::Boolean)];
+        let mut w = ParquetWriter::new(Vec::new(), columns, Codec::Uncompressed).unwrap();
+        for r in rows.iter() {
+            w.write_row(&[r.get("host"), r.get("level"), r.get("timestamp"), r.get("ok")]);
+        }
+        w.flush_row_group().unwrap();
+        w.write_row(&[None, None, None, None]);
+        assert_eq!(w.num_rows(), 3);
+        let out = w.finish().unwrap();
+
+        assert_eq!(&out[..4], b"PAR1");
+        assert_eq!(&out[out.len() - 4..], b"PAR1");
+        let n = out.len();
+        let footer_len = out[n - 8] as usize | (out[n - 7] as usize) << 8 | (out[n - 6] as usize) << 16 | (out[n - 5] as usize) << 24;
+        let footer = &out[n - 8 - footer_len..n - 8];
+        // version 1, then a list of 5 schema elements
+        assert_eq!(&footer[..3], &[0x15, 0x02, 0x19]);
+        assert_eq!(footer[3], 0x5c);
+        // the first page : a data page holding the host column, "a" then "b"
+        let page = &out[4..];
+        assert_eq!(&page[..2], &[0x15, 0x00]);
+        assert!(out.windows(10).any(|w| w == b"\x01\x00\x00\x00a\x01\x00\x00\x00b"));
+        // millisecond timestamps
+        let millis = 1489000000250_i64;
+        let le : Vec<u8> = (0..8).map(|i| (millis >> (i * 8)) as u8).collect();
+        assert!(out.windows(8).any(|w| w == &le[..]));
+    }
+}
@@ -0,0 +1,226 @@
+use std::fmt::{self, Display, Formatter};
+use std::io::Read;
+use std::time::Duration;
+use hyper::{self, Client as HttpClient};
+use hyper::header::ContentType;
+use hyper::status::StatusCode;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use serde::Serialize;
+use serde_json;
+
+/// Data is sent to pachd in requests no larger than this, each one appending to the file
+pub const PUT_CHUNK_SIZE : usize = 8 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Repo {
+    pub name : String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub repo : Repo,
+    pub id : String
+}
+
+#[derive(Serialize)]
+struct File<'a> {
+    commit : &'a Commit,
+    path : &'a str
+}
+
+#[derive(Serialize)]
+struct StartCommitRequest<'a> {
+    parent : Commit,
+    branch : &'a str
+}
+
+#[derive(Serialize)]
+struct PutFileRequest<'a> {
+    file : File<'a>,
+    value : String
+}
+
+#[derive(Serialize)]
+struct CommitRequest<'a> {
+    commit : &'a Commit
+}
+
+#[derive(Debug)]
+pub enum PfsError {
+    /// pachd could not be reached or was not able to serve the request; it is worth trying again
+    Transient(String),
+    /// pachd refused the request, and would refuse it again
+    Permanent(String)
+}
+
+impl PfsError {
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            PfsError::Transient(_) => true,
+            PfsError::Permanent(_) => false
+        }
+    }
+}
+
+impl Display for PfsError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            PfsError::Transient(ref e) => write!(f, "{} (will retry)", e),
+            PfsError::Permanent(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+/// A client for pachd's PFS API, spoken as JSON over HTTP through a gRPC-JSON gateway in front
+/// of pachd, where each method is a `POST` to `/pfs.API/<Method>`.
+pub struct Client {
+    url : String,
+    http : HttpClient
+}
+
+impl Client {
+    pub fn new(url : &str, timeout : Duration) -> Client {
+        let mut http = HttpClient::new();
+        http.set_read_timeout(Some(timeout));
+        http.set_write_timeout(Some(timeout));
+        Client { url : url.trim_right_matches('/').to_owned(), http : http }
+    }
+
+    fn call<T : Serialize>(&self, method : &str, req : &T) -> Result<String, PfsError> {
+        let body = serde_json::to_vec(req).map_err(|e| PfsError::Permanent(e.to_string()))?;
+        let url = format!("{}/pfs.API/{}", self.url, method);
+        let mut res = match self.http.post(&url).header(ContentType::json()).body(&body[..]).send() {
+            Ok(res) => res,
+            Err(hyper::Error::Uri(e)) => return Err(PfsError::Permanent(format!("{} is not a valid url : {}", url, e))),
+            Err(e) => return Err(PfsError::Transient(format!("{} failed : {}", method, e)))
+        };
+        let mut resbody = String::new();
+        if let Err(e) = res.read_to_string(&mut resbody) {
+            return Err(PfsError::Transient(format!("{} failed reading the response : {}", method, e)));
+        }
+        match res.status {
+            s if s.is_success() => Ok(resbody),
+            s @ StatusCode::TooManyRequests | s @ StatusCode::RequestTimeout =>
+                Err(PfsError::Transient(format!("{} returned {} : {}", method, s, resbody))),
+            s if s.is_server_error() => Err(PfsError::Transient(format!("{} returned {} : {}", method, s, resbody))),
+            s => Err(PfsError::Permanent(format!("{} returned {} : {}", method, s, resbody)))
+        }
+    }
+
+    pub fn start_commit(&self, repo : &str, branch : &str) -> Result<Commit, PfsError> {
+        let parent = Commit { repo : Repo { name : repo.to_owned() }, id : branch.to_owned() };
+        let res = self.call("StartCommit", &StartCommitRequest { parent : parent, branch : branch })?;
+        serde_json::from_str(&res).map_err(|e| PfsError::Permanent(format!("StartCommit returned an unexpected response {} : {}", res, e)))
+    }
+
+    /// Appends everything read from `data` to the file at `path` in an open commit, holding one
+    /// request's worth of it in memory at a time.  Failing to read `data` is worth trying again.
+    pub fn put_file<R : Read>(&self, commit : &Commit, path : &str, mut data : R) -> Result<(), PfsError> {
+        let mut chunk = Vec::with_capacity(PUT_CHUNK_SIZE);
+        loop {
+            chunk.clear();
+            (&mut data).take(PUT_CHUNK_SIZE as u64).read_to_end(&mut chunk)
+                .map_err(|e| PfsError::Transient(format!("reading the data for {} failed : {}", path, e)))?;
+            if chunk.is_empty() {
+                return Ok(());
+            }
+            let req = PutFileRequest { file : File { commit : commit, path : path }, value : chunk.to_base64(STANDARD) };
+            self.call("PutFile", &req)?;
+        }
+    }
+
+    pub fn finish_commit(&self, commit : &Commit) -> Result<(), PfsError> {
+        self.call("FinishCommit", &CommitRequest { commit : commit }).map(|_| ())
+    }
+
+    pub fn delete_commit(&self, commit : &Commit) -> Result<(), PfsError> {
+        self.call("DeleteCommit", &CommitRequest { commit : commit }).map(|_| ())
+    }
+
+    /// Commits `data` to `path` on `branch` as a commit of its own.  If any step fails the commit
+    /// is deleted, so a retry never leaves part of the batch behind.
+    pub fn commit_file<R : Read>(&self, repo : &str, branch : &str, path : &str, data : R) -> Result<Commit, PfsError> {
+        let commit = self.start_commit(repo, branch)?;
+        let result = self.put_file(&commit, path, data).and_then(|_| self.finish_commit(&commit));
+        if let Err(e) = result {
+            if let Err(de) = self.delete_commit(&commit) {
+                error!("Failed to delete incomplete commit {} in {} : {}", commit.id, repo, de);
+            }
+            return Err(e);
+        }
+        Ok(commit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Client;
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use hyper::server::{Server, Request, Response};
+    use hyper::status::StatusCode;
+    use hyper::uri::RequestUri;
+    use serde_json::{self, Value as JValue};
+
+    /// Starts a fake pachd which records each call, answering with `status` for `fail_method`
+    fn fake_pachd(fail_method : &'static str, status : StatusCode) -> (String, Arc<Mutex<Vec<(String, JValue)>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        let mut listening = Server::http("127.0.0.1:0").unwrap().handle(move |mut req : Request, mut res : Response| {
+            let method = match req.uri {
+                RequestUri::AbsolutePath(ref p) => p.trim_left_matches("/pfs.API/").to_owned(),
+                _ => String::new()
+            };
+            let mut body = String::new();
+            req.read_to_string(&mut body).unwrap();
+            recorded.lock().unwrap().push((method.clone(), serde_json::from_str(&body).unwrap()));
+            if method == fail_method {
+                *res.status_mut() = status;
+                res.send(b"nope").unwrap();
+            } else if method == "StartCommit" {
+                res.send(br#"{"repo":{"name":"logs"},"id":"abc123"}"#).unwrap();
+            } else {
+                res.send(b"{}").unwrap();
+            }
+        }).unwrap();
+        let url = format!("http://{}", listening.socket);
+        // the server runs for the rest of the test
+        thread::spawn(move || drop(listening));
+        (url, calls)
+    }
+
+#[test]
+    fn pfs_commit_file() {
+        let (url, calls) = fake_pachd("", StatusCode::Ok);
+        let client = Client::new(&url, Duration::from_secs(5));
+        let commit = client.commit_file("logs", "master", "host1", &b"{\"a\":1}\n"[..]).unwrap();
+        assert_eq!(commit.id, "abc123");
+        let calls = calls.lock().unwrap();
+        let methods : Vec<&str> = calls.iter().map(|c| c.0.as_str()).collect();
+        assert_eq!(methods, vec!["StartCommit", "PutFile", "FinishCommit"]);
+        assert_eq!(calls[0].1.pointer("/parent/repo/name").unwrap(), "logs");
+        assert_eq!(calls[0].1.pointer("/branch").unwrap(), "master");
+        assert_eq!(calls[1].1.pointer("/file/commit/id").unwrap(), "abc123");
+        assert_eq!(calls[1].1.pointer("/file/path").unwrap(), "host1");
+        assert_eq!(calls[1].1.pointer("/value").unwrap(), "eyJhIjoxfQo=");
+    }
+
+#[test]
+    fn pfs_classifies_errors() {
+        let (url, calls) = fake_pachd("PutFile", StatusCode::ServiceUnavailable);
+        let client = Client::new(&url, Duration::from_secs(5));
+        let err = client.commit_file("logs", "master", "host1", &b"x"[..]).unwrap_err();
+        assert!(err.is_transient());
+        let methods : Vec<String> = calls.lock().unwrap().iter().map(|c| c.0.clone()).collect();
+        assert_eq!(methods, vec!["StartCommit", "PutFile", "DeleteCommit"]);
+
+        let (url, _) = fake_pachd("StartCommit", StatusCode::NotFound);
+        let err = Client::new(&url, Duration::from_secs(5)).commit_file("logs", "master", "host1", &b"x"[..]).unwrap_err();
+        assert!(!err.is_transient());
+
+        let err = Client::new("http://127.0.0.1:1", Duration::from_secs(5)).start_commit("logs", "master").unwrap_err();
+        assert!(err.is_transient());
+    }
+}
@@ -1,63 +1,227 @@
-use toml::{Table, Value};
 use std::thread::{self, JoinHandle};
-use std::sync::{Arc, Once, ONCE_INIT }; 
+use std::sync::Arc;
 use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
 use serde_json::Value as JValue;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::sync::mpsc::RecvTimeoutError;
 use std::fs::{self, File};
 use std::path::{Path};
+use std::collections::{BTreeMap, HashSet};
 use postgres::{Connection, TlsMode};
-use std::env;
-use output::translator::Translator;
-use std::error::Error;
-
-
-static mut HANDLE: Option<Arc<JoinHandle<()>>> = None;
-static mut CHANNEL: Option<SyncSender<Arc<JValue>>> = None;
-static THREAD: Once = ONCE_INIT;
-
-pub fn spawn(cfg: Table) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
-    THREAD.call_once(|| {
-        let bufmax = 
-            if let Some(bm) = cfg.get("buffer_max") {
-                bm.as_integer().unwrap() as usize
-            } else {
-                1_000_000
-            };
+use postgres::error::{Error as PgError, ConnectError};
+use postgres::params::{ConnectParams, ConnectTarget, IntoConnectParams, UserInfo};
+use postgres::tls::openssl::OpenSsl;
+use openssl::ssl::{SslConnectorBuilder, SslMethod};
+use output::translator::{self, Translator, Mapping};
+use output::parquet_file::ColumnType;
+use metrics::OutputMetrics;
+use config;
+
+pub const DEFAULT_BUFFER_MAX : usize = 1_000_000;
+
+#[derive(RustcDecodable, Debug, Clone, PartialEq)]
+pub struct Config {
+    pub json_schema : Option<String>,
+    pub db_schema : Option<String>,
+    pub batch_directory : Option<String>,
+    pub batch_secs : Option<u64>,
+    pub retry_secs : Option<u64>,
+    pub url : Option<String>,
+    pub url_env : Option<String>,
+    pub url_file : Option<String>,
+    pub host : Option<String>,
+    pub port : Option<u16>,
+    pub database : Option<String>,
+    pub user : Option<String>,
+    pub password : Option<String>,
+    pub password_env : Option<String>,
+    pub password_file : Option<String>,
+    pub sslmode : Option<String>,
+    pub ca_file : Option<String>,
+    pub manage_tables : Option<bool>
+}
+
+/// Whether the connection is encrypted, as libpq's `sslmode`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SslMode {
+    Disable,
+    Prefer,
+    Require
+}
+
+impl Config {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let schemafile = self.json_schema.as_ref().map_or("/etc/lout/schema.json", |s| s.as_str());
+        match translator::fetch_schema(Path::new(schemafile)) {
+            Ok(schema) => for (record_type, mapping) in schema.mappings.iter() {
+                if let Err(e) = column_defs(mapping) {
+                    errors.push(format!("json_schema {} mapping {} : {}", schemafile, record_type, e));
+                }
+            },
+            Err(e) => errors.push(format!("json_schema {} could not be loaded : {}", schemafile, e))
+        }
+        if let Err(e) = self.params() {
+            errors.push(e);
+        }
+        if let Err(e) = self.tls() {
+            errors.push(e);
+        }
+        errors
+    }
+
+    /// Where to connect, from `url` or else from `host`, `port`, `database`, `user` and `password`
+    fn params(&self) -> Result<ConnectParams, String> {
+        let password = config::secret("password", &self.password, &self.password_env, &self.password_file)?;
+        match config::secret("url", &self.url, &self.url_env, &self.url_file)? {
+            Some(url) => {
+                if self.host.is_some() || self.port.is_some() || self.database.is_some() || self.user.is_some() || password.is_some() {
+                    return Err("a url can't be given with a host, port, database, user or password".to_owned());
+                }
+                url.into_connect_params().map_err(|e| format!("url is not a valid postgres url : {}", e))
+            },
+            None => {
+                let database = match self.database {
+                    Some(ref d) => d,
+                    None => return Err("either a url or a database and user must be given".to_owned())
+                };
+                let user = match self.user {
+                    Some(ref u) => u,
+                    None => return Err("either a url or a database and user must be given".to_owned())
+                };
+                let host = self.host.as_ref().map_or("localhost", |h| h.as_str());
+                Ok(ConnectParams {
+                    target : ConnectTarget::Tcp(host.to_owned()),
+                    port : Some(self.port.unwrap_or(5432)),
+                    user : Some(UserInfo { user : user.clone(), password : password }),
+                    database : Some(database.clone()),
+                    options : Vec::new()
+                })
+            }
+        }
+    }
+
+    fn sslmode(&self) -> Result<SslMode, String> {
+        match self.sslmode.as_ref().map_or("disable", |s| s.as_str()) {
+            "disable" if self.ca_file.is_some() => Err("ca_file needs sslmode = \"prefer\" or \"require\"".to_owned()),
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            s => Err(format!("sslmode {} should be disable, prefer or require", s))
+        }
+    }
 
-        let (tx, rx) = sync_channel(bufmax);
-        let handle = thread::spawn(|| {
-            run(cfg, rx);
-        });
-        unsafe {
-            CHANNEL = Some(tx);
-            HANDLE = Some(Arc::new(handle));
+    /// The TLS handshake to use unless `sslmode` is disable, checking the server against `ca_file` if it is given
+    fn tls(&self) -> Result<Option<OpenSsl>, String> {
+        if self.sslmode()? == SslMode::Disable {
+            return Ok(None);
         }
-    });
-    unsafe {
-        (HANDLE.as_ref().unwrap().clone(), CHANNEL.as_ref().unwrap().clone())  
+        let mut builder = SslConnectorBuilder::new(SslMethod::tls()).map_err(|e| e.to_string())?;
+        if let Some(ref ca) = self.ca_file {
+            builder.builder_mut().set_ca_file(ca).map_err(|e| format!("ca_file {} : {}", ca, e))?;
+        }
+        Ok(Some(OpenSsl::from(builder.build())))
     }
 }
 
-fn run(cfg : Table, rx : Receiver<Arc<JValue>>) {
-    let default_dbschema = Value::String("import".to_string());
-    let default_batchdir = Value::String("/lout_postgres".to_string());
-    let default_schemafile = Value::String("/etc/lout/schema.json".to_string());
+/// `name` as a postgres identifier, quoted only if it would otherwise be folded to lowercase or isn't a plain word
+fn quote_ident(name : &str) -> String {
+    let plain = name.chars().next().map_or(false, |c| c == '_' || (c >= 'a' && c <= 'z'))
+        && name.chars().all(|c| c == '_' || (c >= 'a' && c <= 'z') || (c >= '0' && c <= '9'));
+    if plain {
+        name.to_owned()
+    } else {
+        format!("\"{}\"", name.replace("\"", "\"\""))
+    }
+}
 
-    let schemafile = cfg.get("json_schema").unwrap_or(&default_schemafile).as_str().unwrap_or("/etc/lout/schema.json");
-    let dbschema = cfg.get("db_schema").unwrap_or(&default_dbschema).as_str().unwrap_or("import");
+/// `dbschema.tablename` with each part quoted as it needs to be
+fn qualified(dbschema : &str, tablename : &str) -> String {
+    format!("{}.{}", quote_ident(dbschema), quote_ident(tablename))
+}
 
-    let dbport = env::var("DB_PORT").unwrap_or("5432".to_owned());
-    let dbhost = env::var("DB_HOST").unwrap_or("localhost".to_owned());
-    let dbname = env::var("DB_NAME").expect("Please supply a DB_NAME env var");
-    let dbuser = env::var("DB_USER").expect("Please supply a DB_USER env var");
-    let dbpass = env::var("DB_PASS").expect("Please supply a DB_PASS env var");
+/// The Postgres type of a column, for the same typenames as a Parquet output's
+fn pg_type(ty : ColumnType) -> &'static str {
+    match ty {
+        ColumnType::Utf8 => "text",
+        ColumnType::Boolean => "boolean",
+        ColumnType::Int64 => "bigint",
+        ColumnType::Double => "double precision",
+        ColumnType::TimestampMillis => "timestamptz"
+    }
+}
 
-    let batchdir = cfg.get("batch_directory").unwrap_or(&default_batchdir).as_str().unwrap_or("/lout_postgres");
+/// The column definitions for `mapping`, typed by each field's `typename` or as text
+fn column_defs(mapping : &Mapping) -> Result<Vec<String>, String> {
+    mapping.fields.iter().map(|(col, field)| {
+        ColumnType::parse(field.typename.as_ref().map(|t| t.as_str()))
+            .map(|ty| format!("{} {}", quote_ident(col), pg_type(ty)))
+            .map_err(|e| format!("field {} : {}", col, e))
+    }).collect()
+}
 
-    let batch  = cfg.get("batch_secs").unwrap_or(&Value::Integer(300)).as_integer().unwrap_or(300) as u64;
-    let retry  = cfg.get("retry_secs").unwrap_or(&Value::Integer(30)).as_integer().unwrap_or(30) as u64;
+/// Creates `dbschema.tablename` for `mapping` if it doesn't exist, or adds the columns it is missing if it does
+fn sync_table(conn : &Connection, dbschema : &str, tablename : &str, mapping : &Mapping, defs : &[String]) -> Result<(), PgError> {
+    // the names are quoted wherever they aren't already lowercase, so they are stored just as they are
+    let rows = conn.query("SELECT column_name FROM information_schema.columns WHERE table_schema = $1 AND table_name = $2",
+                          &[&dbschema, &tablename])?;
+    let existing : HashSet<String> = rows.iter().map(|row| row.get(0)).collect();
+    let table = qualified(dbschema, tablename);
+    if existing.is_empty() {
+        conn.execute(&format!("CREATE SCHEMA IF NOT EXISTS {}", quote_ident(dbschema)), &[])?;
+        conn.execute(&format!("CREATE TABLE IF NOT EXISTS {} ({})", table, defs.join(", ")), &[])?;
+        info!("created table {}", table);
+        return Ok(());
+    }
+    for (col, def) in mapping.fields.keys().zip(defs) {
+        if !existing.contains(col) {
+            conn.execute(&format!("ALTER TABLE {} ADD COLUMN {}", table, def), &[])?;
+            info!("added column {} to table {}", def, table);
+        }
+    }
+    Ok(())
+}
+
+/// The connection to the database, which is made again on the next batch after it fails
+struct Db {
+    params : ConnectParams,
+    mode : SslMode,
+    tls : Option<OpenSsl>,
+    conn : Option<Connection>
+}
+
+impl Db {
+    fn connection(&mut self) -> Result<&Connection, ConnectError> {
+        if self.conn.is_none() {
+            let tls = match (self.mode, self.tls.as_ref()) {
+                (SslMode::Prefer, Some(tls)) => TlsMode::Prefer(tls),
+                (SslMode::Require, Some(tls)) => TlsMode::Require(tls),
+                _ => TlsMode::None
+            };
+            self.conn = Some(Connection::connect(self.params.clone(), tls)?);
+        }
+        Ok(self.conn.as_ref().unwrap())
+    }
+}
+
+
+pub fn spawn(name: String, cfg: Config, bufmax: usize) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
+    let (tx, rx) = sync_channel(bufmax);
+    let handle = thread::Builder::new().name(name.clone()).spawn(move || {
+        run(name, cfg, rx);
+    }).unwrap();
+    (Arc::new(handle), tx)
+}
+
+fn run(name : String, cfg : Config, rx : Receiver<Arc<JValue>>) {
+    let schemafile = cfg.json_schema.as_ref().map_or("/etc/lout/schema.json", |s| s.as_str());
+    let dbschema = cfg.db_schema.as_ref().map_or("import", |s| s.as_str());
+    let manage_tables = cfg.manage_tables.unwrap_or(true);
+
+    let batchdir = cfg.batch_directory.as_ref().map_or("/lout_postgres", |d| d.as_str());
+
+    let batch  = cfg.batch_secs.unwrap_or(300);
+    let retry  = cfg.retry_secs.unwrap_or(30);
 
     let batch_interval = Duration::from_secs(batch);
     let retry_interval = Duration::from_secs(retry);
@@ -65,11 +229,18 @@ fn run(cfg : Table, rx : Receiver<Arc<JValue>>) {
     let batchpath = Path::new(batchdir);
     let to = Duration::from_millis(100);
 
-    let conn = Connection::connect(format!("postgres://{}:{}@{}:{}/{}", dbuser, dbpass, dbhost, dbport, dbname), TlsMode::None).unwrap();
+    let schema = translator::fetch_schema(Path::new(schemafile)).unwrap();
+    let defs : BTreeMap<String, Vec<String>> = schema.mappings.iter().map(|(name, m)| (name.clone(), column_defs(m).unwrap())).collect();
+    // tables which have been checked against their mapping since they last failed an insert
+    let mut synced = HashSet::new();
+    // tables whose last batch was refused, and is being tried once more before it is set aside
+    let mut refused = HashSet::new();
+    let mut db = Db { params : cfg.params().unwrap(), mode : cfg.sslmode().unwrap(), tls : cfg.tls().unwrap(), conn : None };
 
     if !batchpath.exists() {
         fs::create_dir_all(batchpath).unwrap();
     }
+    let metrics = OutputMetrics::new(&name);
 
     let mut t = Translator::new(
             batchpath,
@@ -80,18 +251,61 @@ fn run(cfg : Table, rx : Receiver<Arc<JValue>>) {
                 let tablename = name.replace("-", "_");
                 match File::open(path) { 
                     Ok(ref mut csvfile) => {
-                        let sql = format!("COPY {}.{} FROM STDIN CSV HEADER", dbschema, tablename);
+                        let mapping = &schema.mappings[name];
+                        let columns : Vec<String> = mapping.fields.keys().map(|c| quote_ident(c)).collect();
+                        let sql = format!("COPY {} ({}) FROM STDIN CSV HEADER", qualified(dbschema, &tablename), columns.join(", "));
                         let now = Instant::now();
-                        match conn.prepare(&sql) {
-                            Ok(stmt) => { 
-                                match stmt.copy_in(&[], csvfile) {
-                                    Err(e) => error!("Failed to insert batch into {} : {:?}", tablename, e),
-                                    _ => {}
-                                }
+                        let copied = match db.connection() {
+                            Ok(conn) => {
+                                let checked = if manage_tables && !synced.contains(&tablename) {
+                                    sync_table(conn, dbschema, &tablename, mapping, &defs[name]).map(|_| { synced.insert(tablename.clone()); })
+                                } else {
+                                    Ok(())
+                                };
+                                checked.and_then(|_| conn.prepare(&sql)).and_then(|stmt| stmt.copy_in(&[], csvfile))
+                            },
+                            Err(e) => {
+                                metrics.errors.inc();
+                                error!("Failed to connect to postgres for batch {}, retrying in {} seconds : {}", tablename, retry, e);
+                                return false;
+                            }
+                        };
+                        match copied {
+                            Ok(_) => { refused.remove(&tablename); },
+                            Err(PgError::Io(e)) => {
+                                // the connection is gone, so the batch is kept for a new one
+                                metrics.errors.inc();
+                                error!("Lost connection inserting batch into {}, retrying in {} seconds : {}", tablename, retry, e);
+                                db.conn = None;
+                                return false;
                             },
-                            Err(e) => error!("Failed to prepare statement : '{}' : {:?}", sql, e)
+                            Err(e) => {
+                                metrics.errors.inc();
+                                synced.remove(&tablename);
+                                if refused.insert(tablename.clone()) {
+                                    // the table may have changed under us, so it is checked again before the retry
+                                    error!("Failed to insert batch into {} with '{}', retrying in {} seconds : {:?}", tablename, sql, retry, e);
+                                    return false;
+                                }
+                                let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                                let failed = batchpath.join(format!("{}.{}.failed", name, secs));
+                                return match fs::rename(path, &failed) {
+                                    Ok(_) => {
+                                        refused.remove(&tablename);
+                                        error!("Failed to insert batch into {} again, moved its {} records to {:?} : {:?}", tablename, num, failed, e);
+                                        true
+                                    },
+                                    Err(re) => {
+                                        error!("Failed to insert batch into {} again, and to move it to {:?}, retrying in {} seconds : {:?} / {}",
+                                               tablename, failed, retry, e, re);
+                                        false
+                                    }
+                                };
+                            }
                         }
                         let dur = now.elapsed();
+                        metrics.batch_size.observe(num as f64);
+                        metrics.batch_seconds.observe_duration(dur);
                         info!("batch {} - {} records inserted in {} milliseconds",
                         tablename, num, dur.subsec_nanos()/1000000);
 
@@ -113,9 +327,14 @@ fn run(cfg : Table, rx : Receiver<Arc<JValue>>) {
         while running { 
             match rx.recv_timeout(to) {
                 Ok(msg) => {  
+                    metrics.depth.dec();
                     t.process(&(*msg));
                 },
-                Err(RecvTimeoutError::Disconnected) => { running = false; error!("Main loop channel disconnected. Shutting down."); }
+                Err(RecvTimeoutError::Disconnected) => {
+                    running = false;
+                    error!("Main loop channel disconnected. Shutting down {}.", name);
+                    t.flush();
+                }
                 Err(RecvTimeoutError::Timeout) => {},
             }
 
@@ -124,3 +343,70 @@ fn run(cfg : Table, rx : Receiver<Arc<JValue>>) {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config { json_schema : None, db_schema : None, batch_directory : None, batch_secs : None, retry_secs : None,
+                 url : None, url_env : None, url_file : None, host : None, port : None, database : None, user : None,
+                 password : None, password_env : None, password_file : None, sslmode : None, ca_file : None, manage_tables : None }
+    }
+
+#[test]
+    fn postgres_connection_config() {
+        let mut cfg = config();
+        assert!(cfg.params().is_err());
+
+        cfg.database = Some("logs".to_owned());
+        cfg.user = Some("lout".to_owned());
+        cfg.password = Some("secret".to_owned());
+        cfg.port = Some(5433);
+        let params = cfg.params().unwrap();
+        assert_eq!(params.port, Some(5433));
+        assert_eq!(params.database, Some("logs".to_owned()));
+
+        cfg.url = Some("postgres://lout@db/logs".to_owned());
+        assert!(cfg.params().is_err());
+
+        let mut cfg = config();
+        cfg.url = Some("postgres://lout:secret@db:5432/logs".to_owned());
+        assert_eq!(cfg.params().unwrap().database, Some("logs".to_owned()));
+
+        assert_eq!(cfg.sslmode(), Ok(SslMode::Disable));
+        cfg.ca_file = Some("/etc/lout/pg-ca.pem".to_owned());
+        assert!(cfg.sslmode().is_err());
+        cfg.sslmode = Some("require".to_owned());
+        assert_eq!(cfg.sslmode(), Ok(SslMode::Require));
+        cfg.sslmode = Some("verify-full".to_owned());
+        assert!(cfg.sslmode().is_err());
+    }
+
+#[test]
+    fn postgres_table_columns() {
+        assert_eq!(quote_ident("level"), "level");
+        assert_eq!(quote_ident("_host2"), "_host2");
+        assert_eq!(quote_ident("Level"), "\"Level\"");
+        assert_eq!(quote_ident("2xx"), "\"2xx\"");
+        assert_eq!(quote_ident("a\"b"), "\"a\"\"b\"");
+        assert_eq!(qualified("import", "web_app"), "import.web_app");
+        assert_eq!(qualified("Import", "web.app"), "\"Import\".\"web.app\"");
+
+        let mapping : Mapping = ::serde_json::from_str(r#"{
+            "fields" : {
+                "message" : { "path" : "/short_message" },
+                "Timestamp" : { "path" : "/timestamp", "typename" : "timestamp" },
+                "level" : { "path" : "/level", "typename" : "integer" },
+                "ok" : { "path" : "/_ok", "typename" : "boolean" }
+            }
+        }"#).unwrap();
+        assert_eq!(column_defs(&mapping).unwrap(),
+                   vec!["\"Timestamp\" timestamptz", "level bigint", "message text", "ok boolean"]);
+
+        let mapping : Mapping = ::serde_json::from_str(r#"{
+            "fields" : { "level" : { "path" : "/level", "typename" : "int); DROP TABLE logs; --" } }
+        }"#).unwrap();
+        assert!(column_defs(&mapping).is_err());
+    }
+}
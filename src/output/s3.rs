@@ -1,150 +1,530 @@
-use toml::{Table, Value};
 use std::thread::{self, JoinHandle};
-use std::sync::{Arc, Once, ONCE_INIT }; 
+use std::sync::Arc;
 use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
 use serde_json::Value as JValue;
 use serde_json::ser;
 use rustc_serialize::base64::{ToBase64, STANDARD};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::sync::mpsc::RecvTimeoutError;
-use std::io::{SeekFrom, Read, Write, Seek};
-use std::fs::{OpenOptions};
+use std::io::{self, Read, Write, BufRead, BufReader};
+use std::fs::{self, File};
+use std::cmp;
 use std::string::String;
-use std::path::PathBuf;
-use rusoto::{DefaultCredentialsProvider, Region};
-use rusoto::default_tls_client;
-use rusoto::s3::{S3Client, PutObjectRequest};
-use chrono::UTC;
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, VecDeque};
+use rusoto_core::{DefaultCredentialsProvider, Region};
+use rusoto_core::default_tls_client;
+use rusoto_s3::{S3, S3Client, PutObjectRequest, CreateMultipartUploadRequest, UploadPartRequest,
+                CompleteMultipartUploadRequest, AbortMultipartUploadRequest, CompletedMultipartUpload, CompletedPart};
+use regex::Regex;
+use chrono::{DateTime, UTC};
 use md5;
+use nix::unistd;
+use metrics::OutputMetrics;
+use output::key_template::KeyTemplate;
+use output::compression::Compression;
 
-static mut HANDLE: Option<Arc<JoinHandle<()>>> = None;
-static mut CHANNEL: Option<SyncSender<Arc<JValue>>> = None;
-static THREAD: Once = ONCE_INIT;
+pub const DEFAULT_BUFFER_MAX : usize = 1_000_000;
+pub const DEFAULT_KEY_TEMPLATE : &'static str = "{timestamp}";
+pub const DEFAULT_PART_SIZE : u64 = 16 * 1024 * 1024;
+// S3 refuses smaller parts, other than the last one
+pub const MIN_PART_SIZE : u64 = 5 * 1024 * 1024;
+const PART_ATTEMPTS : u32 = 5;
 
-pub fn spawn(cfg: Table) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
-    THREAD.call_once(|| {
-        let bufmax = 
-            if let Some(bm) = cfg.get("buffer_max") {
-                bm.as_integer().unwrap() as usize
-            } else {
-                1_000_000
-            };
+#[derive(RustcDecodable, Debug, Clone, PartialEq)]
+pub struct Config {
+    pub region : Option<String>,
+    pub endpoint : Option<String>,
+    pub bucket : Option<String>,
+    pub batch_directory : Option<String>,
+    pub batch_max_size : Option<u64>,
+    pub batch_secs : Option<u64>,
+    pub key_template : Option<String>,
+    pub max_partitions : Option<usize>,
+    pub compression : Option<String>,
+    pub multipart_part_size : Option<u64>,
+    pub retry_max_secs : Option<u64>,
+    pub max_disk_bytes : Option<u64>
+}
+
+lazy_static! {
+    static ref AWS_REGION : Regex = Regex::new(r"^[a-z]{2}(-gov)?-[a-z]+-[0-9]$").unwrap();
+}
+
+impl Config {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Err(e) = self.region() {
+            errors.push(e);
+        }
+        let batch_directory = self.batch_directory.as_ref().map_or("/var/lib/lout", |d| d.as_str());
+        if !Path::new(batch_directory).is_dir() {
+            errors.push(format!("batch_directory {} is not a directory", batch_directory));
+        }
+        if let Err(e) = KeyTemplate::parse(self.key_template.as_ref().map_or(DEFAULT_KEY_TEMPLATE, |t| t.as_str())) {
+            errors.push(e);
+        }
+        if let Err(e) = Compression::parse(self.compression.as_ref().map_or("none", |c| c.as_str())) {
+            errors.push(e);
+        }
+        if self.multipart_part_size.unwrap_or(DEFAULT_PART_SIZE) < MIN_PART_SIZE {
+            errors.push(format!("multipart_part_size must be at least {} bytes", MIN_PART_SIZE));
+        }
+        errors
+    }
+
+    fn region(&self) -> Result<Region, String> {
+        region(self.region.as_ref().map(|r| r.as_str()), self.endpoint.as_ref().map(|e| e.as_str()))
+    }
+}
+
+/// The region to sign requests for.  With an `endpoint` the region is only a name, so any
+/// S3 compatible store such as MinIO or Ceph can be used.
+pub fn region(region : Option<&str>, endpoint : Option<&str>) -> Result<Region, String> {
+    let region = region.unwrap_or("us-east-1");
+    match endpoint {
+        Some(endpoint) => Ok(Region::Custom { name : region.to_owned(), endpoint : endpoint.to_owned() }),
+        None => match region.parse::<Region>() {
+            Ok(r) => Ok(r),
+            // regions newer than rusoto are reached at their standard endpoint
+            Err(_) if AWS_REGION.is_match(region) =>
+                Ok(Region::Custom { name : region.to_owned(), endpoint : format!("https://s3.{}.amazonaws.com", region) }),
+            Err(_) => Err(format!("region {} is not an AWS region, and no endpoint was given", region))
+        }
+    }
+}
+
+pub fn spawn(name: String, cfg: Config, bufmax: usize) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
+    let (tx, rx) = sync_channel(bufmax);
+    let handle = thread::Builder::new().name(name.clone()).spawn(move || {
+        run(name, cfg, rx);
+    }).unwrap();
+    (Arc::new(handle), tx)
+}
+
+/// The batch file for the messages bound for one partition of the key template.  Beside it is
+/// a `.s3key` file holding the partition, so the batch can still be sealed after a restart.
+struct Partition {
+    file : File,
+    path : PathBuf,
+    bytes : u64,
+    count : u64
+}
+
+/// A batch which is closed to new messages, and stays on disk until it has been uploaded to `key`
+struct Sealed {
+    path : PathBuf,
+    key : String,
+    bytes : u64,
+    count : u64
+}
+
+fn key_path(batch : &Path) -> PathBuf {
+    batch.with_extension("s3key")
+}
+
+fn open_partition(path : PathBuf, partition : &str) -> io::Result<Partition> {
+    File::create(key_path(&path)).and_then(|mut f| f.write_all(partition.as_bytes()))?;
+    let file = File::create(&path)?;
+    Ok(Partition { file : file, path : path, bytes : 0, count : 0 })
+}
+
+/// Closes the batch at `path`, fixing its key at the time it was sealed, so a batch which is
+/// retried, or uploaded again after a restart, always goes to the same object
+fn seal(path : &Path, partition : &str, count : u64, now : DateTime<UTC>) -> io::Result<Sealed> {
+    let key = KeyTemplate::key(partition, now);
+    File::create(key_path(path)).and_then(|mut f| f.write_all(key.as_bytes()))?;
+    let sealed = path.with_extension("s3sealed");
+    fs::rename(path, &sealed)?;
+    let bytes = fs::metadata(&sealed)?.len();
+    Ok(Sealed { path : sealed, key : key, bytes : bytes, count : count })
+}
+
+fn remove_batch(path : &Path) {
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(key_path(path));
+}
+
+/// Seals every batch this output left in `dir` when it last stopped, oldest first
+fn recover(name : &str, dir : &Path) -> Vec<Sealed> {
+    let mut found = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("{} could not look for batches left in {:?} : {}", name, dir, e);
+            return found;
+        }
+    };
+    // batch files are named {name}.{start}-{n}, which no other output's files can match
+    let prefix = format!("{}.", name);
+    let now = UTC::now();
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        let ours = path.file_stem().and_then(|s| s.to_str())
+            .map_or(false, |s| s.starts_with(&prefix) && !s[prefix.len()..].contains('.'));
+        if !ours {
+            continue;
+        }
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("s3batch") | Some("s3sealed") => {
+                let mut partition = String::new();
+                if let Err(e) = File::open(key_path(&path)).and_then(|mut f| f.read_to_string(&mut partition)) {
+                    error!("{} cannot upload {:?} without its key, leaving it : {}", name, path, e);
+                    continue;
+                }
+                let count = File::open(&path).map(|f| BufReader::new(f).lines().count() as u64).unwrap_or(0);
+                match seal(&path, &partition, count, now) {
+                    Ok(s) => found.push(s),
+                    Err(e) => error!("{} could not seal {:?} : {}", name, path, e)
+                }
+            },
+            // a compressed copy from an upload which was interrupted
+            Some("s3upload") => { let _ = fs::remove_file(&path); },
+            _ => {}
+        }
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    found
+}
+
+pub fn hostname() -> String {
+    let mut hostbytes = [0u8; 128];
+    unistd::gethostname(&mut hostbytes).unwrap();
+    String::from_utf8(hostbytes.iter().take_while(|c| **c != 0).cloned().collect()).unwrap()
+}
 
-        let (tx, rx) = sync_channel(bufmax);
-        let handle = thread::spawn(|| {
-            run(cfg, rx);
-        });
-        unsafe {
-            CHANNEL = Some(tx);
-            HANDLE = Some(Arc::new(handle));
+/// Sends a single part of a multipart upload, trying it again a few times before giving up on it
+fn put_part(client : &S3, req : &UploadPartRequest) -> Result<Option<String>, String> {
+    let mut attempt = 1;
+    loop {
+        match client.upload_part(req) {
+            Ok(res) => return Ok(res.e_tag),
+            Err(err) => {
+                if attempt >= PART_ATTEMPTS {
+                    return Err(format!("part {} failed {} times : {}", req.part_number, attempt, err));
+                }
+                error!("Failed to put part {} of {}, retrying : {}", req.part_number, req.key, err);
+                thread::sleep(Duration::from_secs(1 << attempt));
+                attempt += 1;
+            }
         }
+    }
+}
+
+/// Sends `file` in parts of `part_size`, holding only one part in memory at a time
+fn put_parts(client : &S3, bucket : &str, key : &str, upload_id : &str, mut file : File, part_size : u64) -> Result<Vec<CompletedPart>, String> {
+    let mut parts = Vec::new();
+    let mut buf = Vec::with_capacity(part_size as usize);
+    loop {
+        buf.clear();
+        (&mut file).take(part_size).read_to_end(&mut buf).map_err(|e| e.to_string())?;
+        if buf.is_empty() {
+            return Ok(parts);
+        }
+        let mut req : UploadPartRequest = Default::default();
+        req.bucket = bucket.to_owned();
+        req.key = key.to_owned();
+        req.upload_id = upload_id.to_owned();
+        req.part_number = parts.len() as i64 + 1;
+        req.content_md5 = Some(md5::compute(buf.as_slice()).to_base64(STANDARD));
+        req.body = Some(buf);
+        let e_tag = put_part(client, &req)?;
+        parts.push(CompletedPart { e_tag : e_tag, part_number : Some(req.part_number) });
+        buf = req.body.take().unwrap();
+    }
+}
+
+/// Uploads the file at `path` to `key`.  Files larger than `part_size` are streamed with a
+/// multipart upload, so memory use doesn't grow with the size of the batch.
+pub fn put_file(client : &S3, bucket : &str, key : &str, path : &Path, encoding : Option<&str>, part_size : u64) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let size = file.metadata().map_err(|e| e.to_string())?.len();
+    if size <= part_size {
+        let mut body = Vec::with_capacity(size as usize);
+        file.read_to_end(&mut body).map_err(|e| e.to_string())?;
+        let mut req : PutObjectRequest = Default::default();
+        req.content_md5 = Some(md5::compute(body.as_slice()).to_base64(STANDARD));
+        req.content_encoding = encoding.map(|e| e.to_owned());
+        req.body = Some(body);
+        req.key = key.to_owned();
+        req.bucket = bucket.to_owned();
+        return client.put_object(&req).map(|_| ()).map_err(|e| e.to_string());
+    }
+
+    let mut create : CreateMultipartUploadRequest = Default::default();
+    create.bucket = bucket.to_owned();
+    create.key = key.to_owned();
+    create.content_encoding = encoding.map(|e| e.to_owned());
+    let upload_id = client.create_multipart_upload(&create).map_err(|e| e.to_string())?
+        .upload_id.ok_or("S3 did not return an upload id".to_owned())?;
+
+    let result = put_parts(client, bucket, key, &upload_id, file, part_size).and_then(|parts| {
+        let mut complete : CompleteMultipartUploadRequest = Default::default();
+        complete.bucket = bucket.to_owned();
+        complete.key = key.to_owned();
+        complete.upload_id = upload_id.clone();
+        complete.multipart_upload = Some(CompletedMultipartUpload { parts : Some(parts) });
+        client.complete_multipart_upload(&complete).map(|_| ()).map_err(|e| e.to_string())
     });
-    unsafe {
-        (HANDLE.as_ref().unwrap().clone(), CHANNEL.as_ref().unwrap().clone())  
+    if result.is_err() {
+        // S3 keeps the parts of an unfinished upload, and bills for them, until it is aborted
+        let mut abort : AbortMultipartUploadRequest = Default::default();
+        abort.bucket = bucket.to_owned();
+        abort.key = key.to_owned();
+        abort.upload_id = upload_id;
+        if let Err(err) = client.abort_multipart_upload(&abort) {
+            error!("Failed to abort the upload of {} : {}", key, err);
+        }
     }
+    result
 }
 
-fn parse_region(region : &str) -> Option<Region> {
-    match region {
-        "us-east-1" => Some(Region::UsEast1),
-        "us-west-1" => Some(Region::UsWest1),
-        "us-west-2" => Some(Region::UsWest2),
-        "ap-northeast-1" => Some(Region::ApNortheast1),
-        "ap-northeast-2" => Some(Region::ApNortheast2),
-        "ap-south-1" => Some(Region::ApSouth1),
-        "ap-southeast-1" => Some(Region::ApSoutheast1),
-        "ap-southeast-2" => Some(Region::ApSoutheast2),
-        "eu-central-1" => Some(Region::EuCentral1),
-        "eu-west-1" => Some(Region::EuWest1),
-        "sa->east-1" => Some(Region::SaEast1),
-        "cn-north-1" => Some(Region::CnNorth1),
-        _ => None
+/// Uploads sealed batches oldest first, removing each from disk once it is in the bucket.
+/// Returns false, leaving the rest for later, as soon as one fails.
+fn upload(name : &str, region : &Region, bucket : &str, compression : Option<Compression>, part_size : u64,
+          sealed : &mut VecDeque<Sealed>, metrics : &OutputMetrics) -> bool {
+    //hack to work around escaping bug
+    println!("{} connecting to S3 at {:?}", name, region);
+    let dcp = match DefaultCredentialsProvider::new() {
+        Ok(result) => { result },
+        Err(err) => {
+            metrics.errors.inc();
+            error!("Failed to discover AWS credentials {}", err);
+            return false;
+        }
+    };
+    let client = S3Client::new(default_tls_client().unwrap(), dcp, region.clone());
+    while let Some(batch) = sealed.pop_front() {
+        let mut key = batch.key.clone();
+        let op_start = Instant::now();
+        let result = match compression {
+            Some(c) => {
+                // compressed next to the batch, so that neither has to fit in memory
+                key.push_str(c.extension());
+                let compressed = batch.path.with_extension("s3upload");
+                let result = File::open(&batch.path)
+                    .and_then(|src| File::create(&compressed).and_then(|dst| c.compress(src, dst)))
+                    .map_err(|e| format!("compression failed : {}", e))
+                    .and_then(|_| put_file(&client, bucket, &key, &compressed, Some(c.content_encoding()), part_size));
+                let _ = fs::remove_file(&compressed);
+                result
+            },
+            None => put_file(&client, bucket, &key, &batch.path, None, part_size)
+        };
+        if let Err(err) = result {
+            metrics.errors.inc();
+            error!("Failed to put object {} message: {}", key, err);
+            sealed.push_front(batch);
+            return false;
+        }
+        remove_batch(&batch.path);
+
+        let op_duration = op_start.elapsed();
+        if batch.count > 0 {
+            metrics.batch_size.observe(batch.count as f64);
+        }
+        metrics.batch_seconds.observe_duration(op_duration);
+        info!("Upload of {} took {:?}", key, op_duration);
     }
+    true
 }
 
-fn run(cfg : Table, rx : Receiver<Arc<JValue>>) {
+/// Seals the batch of every open partition, leaving them to be uploaded
+fn seal_all(name : &str, partitions : &mut HashMap<String, Partition>, sealed : &mut VecDeque<Sealed>) {
+    let now = UTC::now();
+    let mut batches : Vec<(String, Partition)> = partitions.drain().collect();
+    batches.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+    for (partition, p) in batches {
+        drop(p.file);
+        match seal(&p.path, &partition, p.count, now) {
+            Ok(s) => sealed.push_back(s),
+            Err(e) => error!("{} could not seal {:?}, it will be sent after a restart : {}", name, p.path, e)
+        }
+    }
+}
 
-    let default_region = Value::String("us-east-1".to_string());
-    let default_bucket = Value::String("logs".to_string());
-    let default_batchdir = Value::String("/var/lib/lout".to_string());
-    let region            = parse_region(cfg.get("region").unwrap_or(&default_region).as_str().unwrap_or("us-east-1")).unwrap();
-    let batch_directory = cfg.get("batch_directory").unwrap_or(&default_batchdir).as_str().unwrap_or("/var/lib/lout");
-    let bucket          = cfg.get("bucket").unwrap_or(&default_bucket).as_str().unwrap_or("logs");
-    let batch_max      = cfg.get("batch_max_size").unwrap_or(&Value::Integer(1_000_000)).as_integer().unwrap_or(1_000_000) as u64;
-    let batch_secs      = cfg.get("batch_secs").unwrap_or(&Value::Integer(300)).as_integer().unwrap_or(300) as u64;
+/// Discards the oldest sealed batches until everything on disk fits in `max_bytes`
+fn limit_disk(name : &str, max_bytes : u64, partitions : &HashMap<String, Partition>, sealed : &mut VecDeque<Sealed>, metrics : &OutputMetrics) {
+    let mut used = partitions.values().map(|p| p.bytes).sum::<u64>() + sealed.iter().map(|s| s.bytes).sum::<u64>();
+    while used > max_bytes {
+        match sealed.pop_front() {
+            Some(s) => {
+                used -= s.bytes;
+                metrics.errors.inc();
+                error!("{} is using more than max_disk_bytes {}, discarding the batch for {} of {} messages", name, max_bytes, s.key, s.count);
+                remove_batch(&s.path);
+            },
+            None => break
+        }
+    }
+}
+
+fn run(name : String, cfg : Config, rx : Receiver<Arc<JValue>>) {
+
+    let region          = cfg.region().unwrap();
+    let batch_directory = cfg.batch_directory.as_ref().map_or("/var/lib/lout", |d| d.as_str());
+    let bucket          = cfg.bucket.as_ref().map_or("logs", |b| b.as_str());
+    let batch_max       = cfg.batch_max_size.unwrap_or(1_000_000);
+    let batch_secs      = cfg.batch_secs.unwrap_or(300);
+    let template        = KeyTemplate::parse(cfg.key_template.as_ref().map_or(DEFAULT_KEY_TEMPLATE, |t| t.as_str())).unwrap();
+    let max_partitions  = cfg.max_partitions.unwrap_or(100);
+    let compression     = Compression::parse(cfg.compression.as_ref().map_or("none", |c| c.as_str())).unwrap();
+    let part_size       = cmp::max(cfg.multipart_part_size.unwrap_or(DEFAULT_PART_SIZE), MIN_PART_SIZE);
+    let retry_max       = Duration::from_secs(cfg.retry_max_secs.unwrap_or(300));
     let batch_dur = Duration::from_secs(batch_secs);
+    let metrics = OutputMetrics::new(&name);
+    let hostname = hostname();
 
-    let mut batchpath = PathBuf::from(batch_directory);
-    batchpath.push("s3batch");
-    println!("Creating batch file at {:?}", batchpath);
-    let mut batchfile = OpenOptions::new().read(true).append(true).create(true).open(batchpath).unwrap();
+    // outputs may share a batch directory, so the files are named for the output, and for
+    // when it started so they never collide with those left from before a restart
+    println!("Creating batch files for {} in {}", name, batch_directory);
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut sealed : VecDeque<Sealed> = recover(&name, Path::new(batch_directory)).into_iter().collect();
+    if !sealed.is_empty() {
+        println!("{} found {} batches to upload from before it restarted", name, sealed.len());
+    }
+    let mut partitions = HashMap::<String, Partition>::new();
+    let mut next_file = 0_u64;
 
+    let to = Duration::from_millis(100);
+    let mut last = Instant::now();
+    let mut count = 0;
+    let mut backoff = Duration::from_secs(1);
+    let mut retry_at = Instant::now();
     let mut running = true;
-    while running {
-
-        let mut last = Instant::now();
-        let to = Duration::from_millis(100);
-        let mut count = 0;
-        let mut failcount = 0;
-
-        while running && failcount < 20 { 
-            match rx.recv_timeout(to) {
-                Ok(msg) => {  let msgstr = ser::to_string(&msg).unwrap_or(String::new());
-                              writeln!(&batchfile, "{}", msgstr).unwrap();
-                              count += 1; 
-                },
-                Err(RecvTimeoutError::Disconnected) => { running = false; error!("Main loop channel disconnected. Shutting down."); }
-                Err(RecvTimeoutError::Timeout) => {},
+    loop {
+        match rx.recv_timeout(to) {
+            Ok(msg) => {  metrics.depth.dec();
+                          let partition = template.partition(&msg, &name, &hostname);
+                          if !partitions.contains_key(&partition) && partitions.len() >= max_partitions {
+                              // too many partitions are open, so seal them early to make room
+                              seal_all(&name, &mut partitions, &mut sealed);
+                              count = 0;
+                              last = Instant::now();
+                          }
+                          if !partitions.contains_key(&partition) {
+                              let mut path = PathBuf::from(batch_directory);
+                              path.push(format!("{}.{}-{:010}.s3batch", name, started, next_file));
+                              next_file += 1;
+                              match open_partition(path, &partition) {
+                                  Ok(p) => { partitions.insert(partition.clone(), p); },
+                                  Err(e) => error!("{} could not create a batch file in {} : {}", name, batch_directory, e)
+                              }
+                          }
+                          if let Some(p) = partitions.get_mut(&partition) {
+                              let mut msgstr = ser::to_string(&msg).unwrap_or(String::new());
+                              msgstr.push('\n');
+                              match p.file.write_all(msgstr.as_bytes()) {
+                                  Ok(_) => {
+                                      p.bytes += msgstr.len() as u64;
+                                      p.count += 1;
+                                      count += 1;
+                                  },
+                                  Err(e) => {
+                                      metrics.errors.inc();
+                                      error!("{} could not write to {:?} : {}", name, p.path, e);
+                                  }
+                              }
+                          } else {
+                              metrics.errors.inc();
+                          }
+            },
+            Err(RecvTimeoutError::Disconnected) => { running = false; error!("Main loop channel disconnected. Shutting down."); }
+            Err(RecvTimeoutError::Timeout) => {},
+        }
+
+        // on shutdown the final batch is sealed immediately
+        if !running || last.elapsed() > batch_dur || count > batch_max {
+            if count > 0 {
+                seal_all(&name, &mut partitions, &mut sealed);
+                count = 0;
+                if let Some(max_bytes) = cfg.max_disk_bytes {
+                    limit_disk(&name, max_bytes, &partitions, &mut sealed, &metrics);
+                }
             }
-            if last.elapsed() > batch_dur || count > batch_max {
-                // deploy zie batch!
-                //
-                if count > 0 {
-                    //hack to work around escaping bug
-                    println!("Connecting to S3 at {}", region);
-                    let dcp = match DefaultCredentialsProvider::new() {
-                        Ok(result) => { result },
-                        Err(err) => {panic!("Failed to discover AWS credentials {}", err) }
-                    };
-                    let client = S3Client::new(default_tls_client().unwrap(), dcp, region);
-                    let name = UTC::now().to_rfc3339().replace(":","-").replace("+", "-");
-                    let op_start = Instant::now();
-                    let _ = batchfile.seek(SeekFrom::Start(0)).unwrap();
-                    let mut batch_contents = Vec::<u8>::with_capacity(batch_max as usize);
-                    match batchfile.read_to_end(&mut batch_contents) {
-                        Err(why) => panic!("Error opening file to send to S3: {}", why),
-                        Ok(_) => {
-                            let mut req : PutObjectRequest = Default::default();
-                            let hash = md5::compute(batch_contents.as_slice()).to_base64(STANDARD);
-                            req.content_md5 = Some(hash);
-                            req.body = Some(batch_contents);
-                            req.key = name.clone();
-                            req.bucket = bucket.to_string();
-                            if let Err(err) = client.put_object(&req) {
-                                 failcount += 1;
-                                 error!("Failed to put object {} message: {}", name, err);
-                            }
-                        }
-                    }
-                    batchfile.set_len(0).unwrap();
-
-                    let op_duration = op_start.elapsed();
-                    error!("Batch operation took {:?}", op_duration);
-                    if op_duration > batch_dur {
-                        error!("Batch operation took {:?} which is longer than the batch delay {:?}", op_duration, batch_dur);
-                    }
-                    count = 0;
+            last = Instant::now();
+        }
+
+        // messages keep being batched while failed uploads wait to be retried
+        if !sealed.is_empty() && (!running || Instant::now() >= retry_at) {
+            let op_start = Instant::now();
+            if upload(&name, &region, bucket, compression, part_size, &mut sealed, &metrics) {
+                backoff = Duration::from_secs(1);
+                let op_duration = op_start.elapsed();
+                if op_duration > batch_dur {
+                    error!("Batch operation took {:?} which is longer than the batch delay {:?}", op_duration, batch_dur);
                 }
-                last = Instant::now();
+            } else {
+                retry_at = Instant::now() + backoff;
+                error!("{} has {} batches waiting to upload, retrying in {:?}", name, sealed.len(), backoff);
+                backoff = cmp::min(backoff * 2, retry_max);
             }
         }
 
-        if failcount >= 20 {
-            error!("Failed 20 times attempting to connect. Giving up");
-        } else {
-            error!("ES output shutting down gracefully");
+        if !running {
+            if !sealed.is_empty() {
+                error!("{} is shutting down, leaving {} batches in {} for the next start", name, sealed.len(), batch_directory);
+            }
+            break;
         }
     }
 
+    error!("S3 output {} shutting down gracefully", name);
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{Config, open_partition, seal, recover};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use chrono::{UTC, TimeZone};
+    use rusoto_core::Region;
+    use rand::{self, Rng};
+
+#[test]
+    fn s3_regions() {
+        let mut cfg = Config { region : None, endpoint : None, bucket : None, batch_directory : None, batch_max_size : None,
+                               batch_secs : None, key_template : None, max_partitions : None, compression : None,
+                               multipart_part_size : None, retry_max_secs : None, max_disk_bytes : None };
+        assert_eq!(cfg.region(), Ok(Region::UsEast1));
+        cfg.region = Some("sa-east-1".to_owned());
+        assert_eq!(cfg.region(), Ok(Region::SaEast1));
+        cfg.region = Some("ap-southeast-9".to_owned());
+        assert_eq!(cfg.region(), Ok(Region::Custom { name : "ap-southeast-9".to_owned(),
+                                                     endpoint : "https://s3.ap-southeast-9.amazonaws.com".to_owned() }));
+        cfg.region = Some("minio".to_owned());
+        assert!(cfg.region().is_err());
+        cfg.endpoint = Some("http://localhost:9000".to_owned());
+        assert_eq!(cfg.region(), Ok(Region::Custom { name : "minio".to_owned(), endpoint : "http://localhost:9000".to_owned() }));
+    }
+
+#[test]
+    fn s3_recovers_batches() {
+        let mut dir = env::temp_dir();
+        dir.push(format!("lout-s3-{}", rand::thread_rng().gen::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // one batch sealed before a restart, and one still open when lout stopped
+        let mut old = open_partition(dir.join("out.100-0000000000.s3batch"), "a/{timestamp}.json").unwrap();
+        old.file.write_all(b"{}\n{}\n").unwrap();
+        let sealed = seal(&old.path, "a/{timestamp}.json", 2, UTC.timestamp(1489000100, 0)).unwrap();
+        assert_eq!(sealed.key, "a/2017-03-08T19-08-20-00-00.json");
+        let mut open = open_partition(dir.join("out.200-0000000000.s3batch"), "b/{timestamp}.json").unwrap();
+        open.file.write_all(b"{}\n").unwrap();
+        // another output's batch in the same directory
+        open_partition(dir.join("out.other.100-0000000000.s3batch"), "c/{timestamp}").unwrap();
+
+        let found = recover("out", &dir);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].key, "a/2017-03-08T19-08-20-00-00.json");
+        assert_eq!(found[0].count, 2);
+        assert!(found[1].key.starts_with("b/") && !found[1].key.contains("{timestamp}"));
+        assert_eq!(found[1].count, 1);
+        let mut key = String::new();
+        File::open(dir.join("out.200-0000000000.s3key")).unwrap().read_to_string(&mut key).unwrap();
+        assert_eq!(key, found[1].key);
+        assert!(dir.join("out.200-0000000000.s3sealed").exists());
+        assert!(dir.join("out.other.100-0000000000.s3batch").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,61 +1,48 @@
 
-use toml::{Table, Value};
 use std::thread::{self, JoinHandle};
-use std::sync::{Arc, Once, ONCE_INIT }; 
+use std::sync::Arc;
 use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
 use serde_json::Value as JValue;
 use std::time::{Duration, Instant};
+use metrics::OutputMetrics;
 
-static mut HANDLE: Option<Arc<JoinHandle<()>>> = None;
-static mut CHANNEL: Option<SyncSender<Arc<JValue>>> = None;
-static mut COUNT: usize = 0;
-static THREAD: Once = ONCE_INIT;
-
-pub fn spawn(cfg: Table) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
-    THREAD.call_once(|| {
-        let bufmax = 
-            if let Some(bm) = cfg.get("buffer_max") {
-                bm.as_integer().unwrap() as usize
-            } else {
-                10000
-            };
-
-        let (tx, rx) = sync_channel(bufmax);
-        let handle = thread::spawn(|| {
-            unsafe { run(cfg, rx, COUNT) };
-        });
-        unsafe {
-            CHANNEL = Some(tx);
-            HANDLE = Some(Arc::new(handle));
-            COUNT += 1;
-        }
-    });
-    unsafe {
-        (HANDLE.as_ref().unwrap().clone(), CHANNEL.as_ref().unwrap().clone())  
-    }
+pub const DEFAULT_BUFFER_MAX : usize = 10000;
+
+#[derive(RustcDecodable, Debug, Clone, PartialEq)]
+pub struct Config {
+    pub brief : Option<bool>
+}
+
+pub fn spawn(name: String, cfg: Config, bufmax: usize) -> (Arc<JoinHandle<()>>, SyncSender<Arc<JValue>>) {
+    let (tx, rx) = sync_channel(bufmax);
+    let handle = thread::Builder::new().name(name.clone()).spawn(move || {
+        run(name, cfg, rx);
+    }).unwrap();
+    (Arc::new(handle), tx)
 }
 
 
-fn run(cfg : Table, rx : Receiver<Arc<JValue>>, tid : usize) {
+fn run(name : String, cfg : Config, rx : Receiver<Arc<JValue>>) {
 
-    let brief = cfg.get("brief").unwrap_or(&Value::Boolean(false)).as_bool().unwrap_or(false);
+    let metrics = OutputMetrics::new(&name);
+    let brief = cfg.brief.unwrap_or(false);
 
     if brief {
         let mut count = 0_u64;
         let mut last = Instant::now();
         let sec = Duration::new(1, 0);
-        loop { 
-            let _ = rx.recv().unwrap();
+        while let Ok(_) = rx.recv() {
+            metrics.depth.dec();
             count += 1;
             if last.elapsed() > sec {
                 last = Instant::now();
-                println!("{} -- {} msgs / sec", tid, count);
+                println!("{} -- {} msgs / sec", name, count);
                 count = 0;
             }
         }
     } else {
-        loop {
-            let msg = rx.recv().unwrap();
+        while let Ok(msg) = rx.recv() {
+            metrics.depth.dec();
             println!("{}", msg);
         }
     }
@@ -6,23 +6,44 @@ use csv::Writer as CSVWriter;
 use csv::Result as CSVResult;
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use chrono::{UTC, TimeZone, DateTime};
+use output::parquet_file::{ColumnType, integral};
 
 #[derive(Serialize, Deserialize)]
-struct Field {
-    path : String,
-    typename : Option<String>
+pub struct Field {
+    pub path : String,
+    pub typename : Option<String>
 }
 
 #[derive(Serialize, Deserialize)]
-struct Mapping {
-    if_has_key : Option<String>,
-    fields : BTreeMap<String, Field>
+pub struct Mapping {
+    pub if_has_key : Option<String>,
+    pub fields : BTreeMap<String, Field>
 }
 
 #[derive(Serialize, Deserialize)]
-struct Schema {
-    record_type_key : String,
-    mappings : BTreeMap<String, Mapping>
+pub struct Schema {
+    pub record_type_key : String,
+    pub mappings : BTreeMap<String, Mapping>
+}
+
+impl Schema {
+    /// The record type of `jval` and its mapping, if the schema has one and `jval` has the
+    /// mapping's `if_has_key`
+    pub fn mapping_for<'a>(&'a self, jval : &'a JValue) -> Option<(&'a str, &'a Mapping)> {
+        let app = match jval.get(&self.record_type_key).and_then(|a| a.as_str()) {
+            Some(app) => app,
+            None => return None
+        };
+        let mapping = match self.mappings.get(app) {
+            Some(m) => m,
+            None => return None
+        };
+        match mapping.if_has_key {
+            Some(ref ifhk) if jval.get(ifhk).is_none() => None,
+            _ => Some((app, mapping))
+        }
+    }
 }
 
 struct Writer { 
@@ -42,7 +63,42 @@ pub struct Translator<F>
     write_cb  : F
 }
 
-fn fetch_schema(path : &Path) -> Result<Schema, String> {
+/// A GELF timestamp, which is seconds since the epoch, as RFC 3339 so that COPY can put it in a timestamp column
+fn epoch_rfc3339(secs : f64) -> Option<String> {
+    let whole = secs.floor();
+    UTC.timestamp_opt(whole as i64, ((secs - whole) * 1e9) as u32).single().map(|t| t.to_rfc3339())
+}
+
+/// `v` as a cell of a `ty` column, converted as a Parquet output would, or an empty cell, which COPY takes as NULL,
+/// if it is missing or doesn't fit the type
+fn cell(ty : ColumnType, v : Option<&JValue>) -> String {
+    let v = match v {
+        None | Some(&JValue::Null) => return String::new(),
+        Some(v) => v
+    };
+    let cell = match ty {
+        ColumnType::Boolean => match *v {
+            JValue::Bool(b) => Some(b.to_string()),
+            JValue::String(ref s) if s == "true" || s == "false" => Some(s.clone()),
+            _ => None
+        },
+        ColumnType::Int64 => integral(v).map(|i| i.to_string()),
+        // Rust and Postgres spell infinity differently
+        ColumnType::Double => v.as_f64().or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+            .and_then(|f : f64| if f.is_finite() { Some(f.to_string()) } else { None }),
+        ColumnType::TimestampMillis => match v.as_f64() {
+            Some(secs) => epoch_rfc3339(secs),
+            None => v.as_str().and_then(|s| DateTime::parse_from_rfc3339(s).ok()).map(|t| t.to_rfc3339())
+        },
+        ColumnType::Utf8 => Some(match *v {
+            JValue::String(ref s) => s.clone(),
+            ref v => v.to_string()
+        })
+    };
+    cell.unwrap_or(String::new())
+}
+
+pub fn fetch_schema(path : &Path) -> Result<Schema, String> {
     let schemafile = File::open(path).map_err(|e| e.to_string())?;
     serde_json::from_reader(schemafile).map_err(|e| e.to_string())
 }
@@ -99,39 +155,15 @@ impl<F> Translator<F>
 
     pub fn process(&mut self, jval : &JValue) -> Option<u64> 
     {
-        let ref appname = jval.get(&self.schema.record_type_key);
-        if appname.is_none() { return None; }
-        let app = appname.unwrap().as_str().unwrap();
-        let mapping = self.schema.mappings.get(app);
-        if mapping.is_none() { return None; }
-        let mapping = mapping.unwrap();
-        match mapping.if_has_key {
-            Some(ref ifhk) => {
-                if jval.get(ifhk).is_none() {
-                    return None
-                }
-            },
-            None => {}
-        }
+        let (app, mapping) = match self.schema.mapping_for(jval) {
+            Some(m) => m,
+            None => return None
+        };
         let mut result = Vec::<String>::with_capacity(mapping.fields.len());
 
         for (_, field) in mapping.fields.iter() {
-            match jval.pointer(&field.path) {
-                Some(val) => {
-                    if val.is_number() {
-                        match val.as_f64() {
-                            Some(v) => result.push(v.to_string()),
-                            None => result.push("0".to_owned())
-                        }
-                    } else {
-                        match val.as_str() {
-                            Some(v) => result.push(v.to_owned()),
-                            None => result.push(String::new())
-                        }
-                    }
-                },
-                None => {result.push(String::new())}
-            }
+            let ty = ColumnType::parse(field.typename.as_ref().map(|t| t.as_str())).unwrap_or(ColumnType::Utf8);
+            result.push(cell(ty, jval.pointer(&field.path)));
         }
 
         if result.is_empty() {
@@ -158,4 +190,47 @@ impl<F> Translator<F>
         }
         Some(writer.count)
     }
+
+    /// Hands every file with pending records to the write callback, regardless of its interval
+    pub fn flush(&mut self) {
+        for (app, writer) in self.outfiles.iter_mut() {
+            if writer.count == 0 {
+                continue;
+            }
+            writer.csvwriter.flush().unwrap();
+            let ref mut cb = self.write_cb;
+            if cb(&writer.path, app, writer.count) {
+                writer.count = 0;
+                let columns : Vec<&String> = self.schema.mappings[app].fields.keys().collect();
+                writer.csvwriter = Self::new_writer(&writer.path, true, &columns).unwrap();
+            }
+            writer.next_write = Instant::now() + self.write_interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+#[test]
+    fn translator_timestamps() {
+        assert_eq!(epoch_rfc3339(1489000100.0), Some("2017-03-08T19:08:20+00:00".to_owned()));
+        assert_eq!(epoch_rfc3339(1489000100.5), Some("2017-03-08T19:08:20.500+00:00".to_owned()));
+    }
+
+#[test]
+    fn translator_cells() {
+        let cells = |ty, json : &str| -> Vec<String> {
+            let vals : Vec<JValue> = serde_json::from_str(json).unwrap();
+            vals.iter().map(|v| cell(ty, Some(v))).collect()
+        };
+        assert_eq!(cells(ColumnType::Boolean, r#"[true, "false", 1, "yes", null]"#), vec!["true", "false", "", "", ""]);
+        assert_eq!(cells(ColumnType::Int64, r#"[3, 4.0, 4.5, "12", "x", true]"#), vec!["3", "4", "", "12", "", ""]);
+        assert_eq!(cells(ColumnType::Double, r#"[1.5, 2, "0.25", "inf", "x"]"#), vec!["1.5", "2", "0.25", "", ""]);
+        assert_eq!(cells(ColumnType::TimestampMillis, r#"[1489000100, "2017-03-08T19:08:20Z", "yesterday", false]"#),
+                   vec!["2017-03-08T19:08:20+00:00", "2017-03-08T19:08:20+00:00", "", ""]);
+        assert_eq!(cells(ColumnType::Utf8, r#"["a,b", 7, true, {"k":1}]"#), vec!["a,b", "7", "true", r#"{"k":1}"#]);
+        assert_eq!(cell(ColumnType::Int64, None), "");
+    }
 }
@@ -1,48 +1,96 @@
 
-use std::net::{SocketAddr, ToSocketAddrs };
+use std::net::SocketAddr;
 use std::collections::HashMap;
-use toml::Table;
 use std::sync::mpsc::{SyncSender};
 use serde_json::Value as JValue;
 use std::fmt::{self, Display, Debug, Formatter};
 use std::thread::{JoinHandle};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::TrySendError;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use output;
-
-#[derive(Debug)]
-pub enum Filter {
-    IfHasField(String)
-}
+use filter::Filter;
+use gelf;
+use metrics::{self, Counter, Gauge};
+use config::{self, Config, InputConfig};
 
 pub struct Output {
     pub output_name : String,
     pub route_name : String,
     pub filter : Option<Filter>,
     pub channel : SyncSender<Arc<JValue>>,
-    pub thread_handle : Arc<JoinHandle<()>>
+    pub thread_handle : Arc<JoinHandle<()>>,
+    dropped : AtomicUsize,
+    matched_count : Counter,
+    dropped_count : Counter,
+    depth : Gauge
+}
+
+impl Output {
+    fn accepts(&self, msg : &JValue) -> bool {
+        match self.filter {
+            Some(ref f) => f.matches(msg),
+            None => true
+        }
+    }
+
+    fn send(&self, msg : Arc<JValue>) {
+        match self.channel.try_send(msg) {
+            Ok(()) => self.depth.inc(),
+            Err(TrySendError::Full(_)) => {
+                self.dropped_count.inc();
+                let fc = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if fc % 100 == 0 { println!("Failed to send to output {}, buffer is full", self.output_name) };},
+            Err(TrySendError::Disconnected(_)) => panic!("Downstream reader has failed for {}", self.output_name)
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    Http
+}
+
+impl Protocol {
+    pub fn parse(p : &str) -> Option<Protocol> {
+        match p {
+            "udp" => Some(Protocol::Udp),
+            "tcp" => Some(Protocol::Tcp),
+            "http" => Some(Protocol::Http),
+            _ => None
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Input {
     pub name : String,
     pub addr : SocketAddr,
-    pub buffer_sz : usize
+    pub protocol : Protocol,
+    pub buffer_sz : usize,
+    pub chunk_timeout : Duration,
+    pub max_pending_messages : usize,
+    pub max_pending_bytes : usize
 }
 
 impl Input {
-    pub fn new(name : String, cfg : &Table) -> Input {
-        let buffer_sz = 
-            if let Some(sz) = cfg.get("buffer_size") {
-                sz.as_integer().unwrap() as usize
-            } else {
-                8_usize * 1024
-            };
-        let addr = cfg["url"].as_str().unwrap().to_socket_addrs().unwrap().next().unwrap(); //required
+    /// Builds an input from a config which has already been validated
+    pub fn new(name : String, cfg : &InputConfig) -> Input {
+        let addr = config::resolve(&cfg.url).unwrap();
+        let protocol = cfg.protocol.as_ref().map_or(Some(Protocol::Udp), |p| Protocol::parse(p)).unwrap();
+        let chunk_timeout = cfg.chunk_timeout_secs.unwrap_or(gelf::DEFAULT_CHUNK_TIMEOUT_SECS);
 
         Input {
             name : name,
             addr : addr,
-            buffer_sz : buffer_sz,
+            protocol : protocol,
+            buffer_sz : cfg.buffer_size.unwrap_or(8 * 1024),
+            chunk_timeout : Duration::from_secs(chunk_timeout),
+            max_pending_messages : cfg.max_pending_messages.unwrap_or(gelf::DEFAULT_MAX_PENDING_MESSAGES),
+            max_pending_bytes : cfg.max_pending_bytes.unwrap_or(gelf::DEFAULT_MAX_PENDING_BYTES),
         }
     }
 
@@ -50,45 +98,71 @@ impl Input {
 
 pub type Routes = HashMap<String, Route>;
 
+/// The route an input delivers to, shared with the input's task or thread.
+/// Emptying it stops delivery and releases the route's output channels.
+pub type SharedRoute = Arc<Mutex<Option<Route>>>;
+
+/// Delivers a message along a shared route, returning false once the route has been emptied
+pub fn dispatch(route : &SharedRoute, msg : Arc<JValue>) -> bool {
+    if let Some(ref r) = *route.lock().unwrap() {
+        r.dispatch(msg);
+        true
+    } else {
+        false
+    }
+}
+
 pub struct Route {
     input : Input,
     outputs : Vec<Output>,
 }
 
 impl Route {
-    pub fn with_config(config : Table) -> Routes {
+    /// Builds the routes for a config which has already been validated, keyed by input name.
+    /// `workers` must hold a running worker for every output the routes refer to, and routes
+    /// which share an output share its worker.
+    pub fn with_config(config : &Config, workers : &HashMap<String, output::Worker>) -> Routes {
 
         let mut route_map = HashMap::<String, Route>::new();
 
-        for (name, route) in config["route"].as_table().unwrap().iter() {
+        for (name, routecfg) in config.routes.iter() {
 
-            println!("processing {}", name);
             let mut filter : Option<Filter> = None;
-            let routetbl = route.as_table().unwrap();
-            let input_name = routetbl["input"].as_str().unwrap().to_string();
-            let inputtbl = config["input"].as_table().unwrap();
+            let input_name = routecfg.input.clone();
             let routes = route_map.entry(input_name.clone()).or_insert(
-                Route { input :  Input::new(input_name.clone(), inputtbl[&input_name].as_table().unwrap()),
+                Route { input :  Input::new(input_name.clone(), &config.inputs[&input_name]),
                         outputs : Vec::new() } );
-            let output_name = routetbl["output"].as_str().unwrap().to_string(); //required
-            let output = config["output"].as_table().unwrap();
-            let outputtbl = output[&output_name].as_table().unwrap();
-            let (outthread, outchan) = match outputtbl.get("type").map(|t| t.as_str().unwrap()) {
-                Some("s3") => output::s3::spawn(outputtbl.clone()),
-                Some("es") | Some("elasticsearch") => output::es::spawn(outputtbl.clone()),
-                Some("stdout") => output::stdout::spawn(outputtbl.clone()),
-                Some("postgres") => output::postgres::spawn(outputtbl.clone()),
-                _ => panic!("{} is not found or is not a valid output type", "route::type" )
-            };
-            if let Some(field) = routetbl.get("if_has_field") {
-                filter = Some(Filter::IfHasField(field.as_str().unwrap().to_string()));
+            let output_name = routecfg.output.clone();
+            let (outthread, outchan) = workers[&output_name].clone();
+            if let Some(ref field) = routecfg.if_has_field {
+                filter = Some(Filter::Exists(field.clone()));
+            }
+            if let Some(ref expr) = routecfg.filter {
+                let f = match Filter::parse(expr) {
+                    Ok(f) => f,
+                    Err(e) => panic!("invalid filter for route {} : {}", name, e)
+                };
+                filter = Some(match filter {
+                    Some(hf) => Filter::And(Box::new(hf), Box::new(f)),
+                    None => f
+                });
             }
 
+            let (matched_count, dropped_count) = {
+                let labels = [("route", name.as_str()), ("output", output_name.as_str())];
+                (metrics::counter("lout_route_matches_total", "Messages accepted by a route's filter", &labels),
+                 metrics::counter("lout_output_dropped_total", "Messages dropped because the output channel was full", &labels))
+            };
+            let depth = metrics::OutputMetrics::new(&output_name).depth;
             let output = Output { output_name : output_name,
                                   route_name  : name.clone(),
                                   filter      : filter,
                                   thread_handle : outthread,
-                                  channel       : outchan} ;
+                                  channel       : outchan,
+                                  dropped       : AtomicUsize::new(0),
+                                  matched_count : matched_count,
+                                  dropped_count : dropped_count,
+                                  depth         : depth} ;
             (*routes).outputs.push(output);
         }
         route_map
@@ -98,8 +172,14 @@ impl Route {
         self.input.clone()
     }
 
-    pub fn get_outputs(& self) -> &Vec<Output> {
-         &self.outputs
+    /// Hands a message to every output on this route whose filter accepts it
+    pub fn dispatch(&self, msg : Arc<JValue>) {
+        for o in self.outputs.iter() {
+            if o.accepts(&msg) {
+                o.matched_count.inc();
+                o.send(msg.clone());
+            }
+        }
     }
 }
 
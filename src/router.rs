@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use tokio_core::reactor::Handle;
+use config::{Config, InputConfig, OutputConfig};
+use route::{Route, SharedRoute, Protocol};
+use input::{self, Listener};
+use output::{self, Worker};
+
+struct RunningInput {
+    cfg : InputConfig,
+    route : SharedRoute,
+    listener : Option<Listener>
+}
+
+struct RunningOutput {
+    cfg : OutputConfig,
+    worker : Worker
+}
+
+/// Owns everything started from the config : the inputs, the routes they deliver along and
+/// the output workers.  A new config is applied by diffing it against what is running, so
+/// unchanged inputs and outputs keep their sockets, channels and batches.
+pub struct Router {
+    path : String,
+    modified : Option<SystemTime>,
+    config : Config,
+    inputs : HashMap<String, RunningInput>,
+    outputs : HashMap<String, RunningOutput>,
+    // inputs waiting to bind their address, with when to next try : a restarted input waits for its
+    // old socket to close, and one which failed to bind tries again every BIND_RETRY_SECS
+    pending : Vec<(String, Instant)>,
+    // removed outputs which are draining their final batch
+    retired : Vec<(String, Arc<JoinHandle<()>>)>
+}
+
+const BIND_RETRY_SECS : u64 = 5;
+
+fn modified(path : &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// HTTP inputs can't close their socket, so they keep listening until lout exits
+fn is_http(cfg : &InputConfig) -> bool {
+    cfg.protocol.as_ref().and_then(|p| Protocol::parse(p)) == Some(Protocol::Http)
+}
+
+impl Router {
+    pub fn start(path : &str, config : Config, handle : &Handle) -> Router {
+        let mut router = Router {
+            path : path.to_string(),
+            modified : modified(path),
+            config : config.clone(),
+            inputs : HashMap::new(),
+            outputs : HashMap::new(),
+            pending : Vec::new(),
+            retired : Vec::new()
+        };
+        router.apply(config, handle);
+        // an input which can't bind at startup is fatal
+        if router.inputs.values().any(|i| i.listener.is_none()) {
+            panic!("Failed to start every input");
+        }
+        router
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Called from the event loop.  Reloads the config if asked to, or if `watch_config` is set
+    /// and the file has changed, and starts any inputs which are waiting for their address.
+    pub fn tick(&mut self, reload : bool, handle : &Handle) {
+        let changed = self.config.watch_config && modified(&self.path) != self.modified;
+        if reload || changed {
+            self.reload(handle);
+        }
+        let now = Instant::now();
+        for (name, at) in self.pending.split_off(0) {
+            if at <= now {
+                self.start_input(name, handle);
+            } else {
+                self.pending.push((name, at));
+            }
+        }
+        self.prune_retired();
+    }
+
+    /// Joins retired outputs which have sent their final batch, so they don't pile up across reloads
+    fn prune_retired(&mut self) {
+        for (name, worker) in self.retired.split_off(0) {
+            if !worker.is_finished() {
+                self.retired.push((name, worker));
+                continue;
+            }
+            match Arc::try_unwrap(worker) {
+                Ok(h) => if h.join().is_err() { error!("Output {} panicked while draining", name) },
+                Err(worker) => self.retired.push((name, worker))
+            }
+        }
+    }
+
+    /// Re-reads the config file and applies it, keeping the running config if it is not valid
+    pub fn reload(&mut self, handle : &Handle) {
+        self.modified = modified(&self.path);
+        println!("Reloading config from {}", self.path);
+        match Config::load(&self.path) {
+            Ok(config) => {
+                if config.metrics.as_ref().map(|m| &m.url) != self.config.metrics.as_ref().map(|m| &m.url) {
+                    error!("The [metrics] url can only be changed by restarting lout");
+                }
+                self.apply(config.clone(), handle);
+                self.config = config;
+                println!("Config reloaded");
+            },
+            Err(errors) => {
+                for e in errors.iter() {
+                    error!("{} : {}", self.path, e);
+                }
+                error!("Config in {} is not valid, keeping the running config", self.path);
+            }
+        }
+    }
+
+    fn apply(&mut self, config : Config, handle : &Handle) {
+        // only outputs which something routes to are started
+        let mut outputs = HashMap::new();
+        for r in config.routes.values() {
+            if outputs.contains_key(&r.output) {
+                continue;
+            }
+            let cfg = config.outputs[&r.output].clone();
+            let worker = match self.outputs.remove(&r.output) {
+                Some(ref old) if old.cfg == cfg => old.worker.clone(),
+                Some(old) => {
+                    println!("Restarting output {} once it has drained", r.output);
+                    output::spawn_after(&r.output, &cfg, old.worker.0)
+                },
+                None => {
+                    println!("Starting output {}", r.output);
+                    output::spawn(&r.output, &cfg)
+                }
+            };
+            outputs.insert(r.output.clone(), RunningOutput { cfg : cfg, worker : worker });
+        }
+        for (name, old) in self.outputs.drain() {
+            println!("Retiring output {}", name);
+            self.retired.push((name, old.worker.0));
+        }
+        self.outputs = outputs;
+
+        let workers : HashMap<String, Worker> = self.outputs.iter().map(|(name, o)| (name.clone(), o.worker.clone())).collect();
+        let mut routes = Route::with_config(&config, &workers);
+
+        let mut inputs = HashMap::new();
+        let mut added = Vec::new();
+        for (name, route) in routes.drain() {
+            let cfg = config.inputs[&name].clone();
+            match self.inputs.remove(&name) {
+                Some(old) => if old.cfg == cfg || is_http(&old.cfg) {
+                    if old.cfg != cfg {
+                        error!("HTTP input {} can only be changed by restarting lout, it keeps listening as before", name);
+                    }
+                    // swapped in one step, so no message sees the input without a route.  The old
+                    // route, and with it the channels of any retired outputs, is dropped outside the lock.
+                    let _previous = mem::replace(&mut *old.route.lock().unwrap(), Some(route));
+                    inputs.insert(name, old);
+                } else {
+                    println!("Restarting input {}", name);
+                    old.route.lock().unwrap().take();
+                    drop(old.listener);
+                    inputs.insert(name.clone(), RunningInput { cfg : cfg, route : Arc::new(Mutex::new(Some(route))), listener : None });
+                    self.pending.push((name, Instant::now()));
+                },
+                None => {
+                    inputs.insert(name.clone(), RunningInput { cfg : cfg, route : Arc::new(Mutex::new(Some(route))), listener : None });
+                    added.push(name);
+                }
+            }
+        }
+        for (name, old) in self.inputs.drain() {
+            println!("Retiring input {}", name);
+            old.route.lock().unwrap().take();
+            if is_http(&old.cfg) {
+                // still bound, so it is kept to be given a route again if it comes back
+                inputs.insert(name, old);
+            }
+        }
+        self.inputs = inputs;
+        for name in added {
+            self.start_input(name, handle);
+        }
+    }
+
+    fn start_input(&mut self, name : String, handle : &Handle) {
+        if let Some(running) = self.inputs.get_mut(&name) {
+            let input = match *running.route.lock().unwrap() {
+                Some(ref r) => r.get_input(),
+                None => return
+            };
+            match input::spawn(input, running.route.clone(), handle) {
+                Ok(l) => running.listener = Some(l),
+                Err(e) => {
+                    error!("Failed to start input {}, retrying in {} seconds : {}", name, BIND_RETRY_SECS, e);
+                    self.pending.push((name, Instant::now() + Duration::from_secs(BIND_RETRY_SECS)));
+                }
+            }
+        }
+    }
+
+    /// Stops every input and waits for each output to send its final batch and exit
+    pub fn shutdown(mut self) {
+        for (_, i) in self.inputs.drain() {
+            i.route.lock().unwrap().take();
+        }
+        let workers = self.outputs.drain().map(|(name, o)| (name, o.worker.0));
+        for (name, worker) in workers.chain(self.retired.drain(..)) {
+            match Arc::try_unwrap(worker) {
+                Ok(h) => if h.join().is_err() { error!("Output {} panicked while shutting down", name) },
+                Err(_) => error!("Output {} is still in use, not waiting for it", name)
+            }
+        }
+    }
+}
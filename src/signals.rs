@@ -0,0 +1,35 @@
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use nix::libc::c_int;
+use nix::sys::signal::{self, sigaction, SigAction, SigHandler, SaFlags, SigSet};
+
+static SHUTDOWN: AtomicBool = ATOMIC_BOOL_INIT;
+static RELOAD: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern fn on_shutdown(_ : c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+extern fn on_reload(_ : c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Installs handlers so that SIGTERM and SIGINT request a graceful shutdown,
+/// and SIGHUP requests that the config be reloaded
+pub fn install() {
+    let sa = SigAction::new(SigHandler::Handler(on_shutdown), SaFlags::empty(), SigSet::empty());
+    let hup = SigAction::new(SigHandler::Handler(on_reload), SaFlags::empty(), SigSet::empty());
+    unsafe {
+        sigaction(signal::SIGTERM, &sa).unwrap();
+        sigaction(signal::SIGINT, &sa).unwrap();
+        sigaction(signal::SIGHUP, &hup).unwrap();
+    }
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// True once for each SIGHUP received since it was last asked
+pub fn reload_requested() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}